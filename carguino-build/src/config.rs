@@ -1,4 +1,5 @@
-use {Result, ResultExt};
+use {ErrorKind, Result, ResultExt};
+use library::{LibraryProperties, Precompiled};
 use prefs::Preferences;
 
 use bindgen::{self, Builder as BindgenBuilder};
@@ -7,91 +8,226 @@ use regex::{Captures, Regex};
 
 use serde_json;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::ffi::OsStr;
-use std::fs;
-use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Command, Output, Stdio};
+use std::time::Instant;
+
+// Bump whenever the serialized fields of `Config` change.
+const CONFIG_VERSION: u32 = 12;
+
+const SECTION_FLAGS: &'static [&'static str] = &["-ffunction-sections", "-fdata-sections"];
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
+    version: u32,
+    board_id: String,
     core: String,
     arch: String,
     board: String,
+    mcu: String,
     llvm_target: String,
 
     core_path: PathBuf,
-    variant_path: PathBuf,
+    variant_path: Option<PathBuf>,
 
-    library_paths: HashMap<String, PathBuf>,
+    library_paths: BTreeMap<String, PathBuf>,
 
     c_system_includes: Vec<PathBuf>,
     cpp_system_includes: Vec<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+
+    defines: Vec<String>,
 
     c_compiler: Recipe,
     cpp_compiler: Recipe,
-    assembler: Recipe,
-    archiver: Recipe
+    assembler: Option<Recipe>,
+    archiver: Option<Recipe>,
+
+    variant_includes_first: bool,
+
+    // All of the board's preferences, expanded, for build scripts that need more than the fields above.
+    prefs: BTreeMap<String, String>,
+
+    link: bool,
+    // Writing a new request to this file makes the core's build script run again and recompile the core.
+    rebuild_core_file: Option<PathBuf>,
+
+    // Where the build script writes its output, which is not part of the configuration carguino passes on.
+    #[serde(skip_serializing, skip_deserializing)]
+    out_dir: PathBuf,
+    #[serde(skip_serializing, skip_deserializing)]
+    flags: Flags
+}
+
+// Options that only change what the build scripts report are passed in `$CARGUINO_FLAGS` instead, as cargo runs every
+// build script again when `$CARGUINO_CONFIG` changes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Flags {
+    verbose: bool,
+    quiet: bool,
+    dump_objects: bool,
+    timings_file: Option<PathBuf>
+}
+
+#[doc(hidden)]
+#[derive(Debug, Default)]
+pub struct Options {
+    pub board_id: String,
+    pub archiver_override: Option<PathBuf>,
+    pub recipe_overrides: HashMap<String, String>,
+    pub variant_includes_first: bool,
+    pub includes_cache_dir: Option<PathBuf>,
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Vec<String>,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub link: bool,
+    pub dump_objects: bool,
+    pub rebuild_core_file: Option<PathBuf>,
+    pub timings_file: Option<PathBuf>
 }
 
 impl Config {
     #[doc(hidden)]
-    pub fn serialize(mut prefs: Preferences, llvm_target: &str, arch: &str, library_paths: HashMap<String, PathBuf>) -> Result<String> {
+    pub fn serialize(mut prefs: Preferences, llvm_target: &str, arch: &str, library_paths: HashMap<String, PathBuf>,
+                     options: Options) -> Result<String> {
+        for (key, pattern) in options.recipe_overrides {
+            validate_recipe_override(&key, &pattern)?;
+            prefs.set(&key, pattern);
+        }
+
         prefs.set("source_file", "%source_file");
         prefs.set("object_file", "%object_file");
+        prefs.set("object_files", "%object_files");
         prefs.set("includes", "%includes");
-        prefs.set("archive_file", "%archive_file");
+        // As in the Arduino IDE, `archive_file` is only the file name and `archive_file_path` the full path. Older
+        // platforms combine the former with `build.path`, which points to arduino-builder's own build directory.
+        if let Some(pattern) = prefs.get_unexpanded::<String>("recipe.ar.pattern") {
+            prefs.set("recipe.ar.pattern", pattern.replace("{build.path}/{archive_file}", "{archive_file_path}"));
+        }
+        prefs.set("archive_file", "%archive_file_name");
         prefs.set("archive_file_path", "%archive_file");
+        let expanded_prefs = prefs.keys().filter_map(|key| {
+            prefs.get::<String>(key).map(|value| (key.clone(), value))
+        }).collect();
 
         let core = prefs.get::<String>("build.core")
                         .map_or_else(|| Err("'build.core' missing from preferences"), Ok)?;
 
-        let board = prefs.get::<String>("build.board")
-                         .map(|s| s.to_lowercase())
-                         .map_or_else(|| Err("'build.board' missing from preferences"), Ok)?;
+        let board_define = prefs.get::<String>("build.board")
+                                .map_or_else(|| Err("'build.board' missing from preferences"), Ok)?;
+        let board = board_define.to_lowercase();
+
+        let mcu = prefs.get::<String>("build.mcu")
+                       .map_or_else(|| Err("'build.mcu' missing from preferences"), Ok)?;
 
         let core_path = prefs.get::<String>("build.core.path")
                              .map(PathBuf::from)
                              .map_or_else(|| Err("'build.core.path' missing from preferences"), Ok)?;
-        let variant_path = prefs.get::<String>("build.variant.path")
-                                .map(PathBuf::from)
-                                .map_or_else(|| Err("'build.variant.path' missing from preferences"), Ok)?;
+        // Some platforms define boards without a variant directory.
+        let variant_path = prefs.get::<String>("build.variant").and_then(|variant| {
+            if variant.is_empty() { None } else { prefs.get::<String>("build.variant.path") }
+        }).map(PathBuf::from);
+
+        let c_compiler = Recipe::from_prefs(&prefs, "c.o")?;
+        let cpp_compiler = Recipe::from_prefs(&prefs, "cpp.o")?;
+        // Not every core compiles assembly or defines its own archiver.
+        let assembler = Recipe::optional(&prefs, "S.o");
+        let archiver = match options.archiver_override {
+            Some(command) => Some(Recipe::optional(&prefs, "ar").unwrap_or_else(Recipe::default_archiver).with_command(command)),
+            None => Recipe::optional(&prefs, "ar")
+        };
 
-        let c_compiler = Recipe::from_prefs(&prefs, "c.o");
-        let cpp_compiler = Recipe::from_prefs(&prefs, "cpp.o");
-        let assembler = Recipe::from_prefs(&prefs, "S.o");
-        let archiver = Recipe::from_prefs(&prefs, "ar");
+        let cache_dir = options.includes_cache_dir.as_ref().map(PathBuf::as_path);
+        let c_system_includes = cached_system_includes(cache_dir, c_compiler.command().as_os_str(),
+                                                       &["-w", "-v", "-E", "-xc", "-"]);
+        let cpp_system_includes = cached_system_includes(cache_dir, cpp_compiler.command().as_os_str(),
+                                                         &["-w", "-v", "-E", "-xc++", "-"]);
 
-        let c_system_includes = get_system_includes(c_compiler.command().as_os_str(), &["-w", "-v", "-E", "-xc", "-"]);
-        let cpp_system_includes = get_system_includes(cpp_compiler.command().as_os_str(), &["-w", "-v", "-E", "-xc++", "-"]);
+        // The same defines the IDE passes to every compilation; recipes that set them already keep their values.
+        let mut defines = vec![
+            format!("-DARDUINO={}", prefs.get::<String>("runtime.ide.version").unwrap_or_else(|| "10800".to_string())),
+            format!("-DARDUINO_{}", board_define),
+            format!("-DARDUINO_ARCH_{}", arch.to_uppercase())
+        ];
+        defines.extend(options.defines);
 
         let config = Config {
+            version: CONFIG_VERSION,
+            board_id: options.board_id,
             core: core,
             arch: arch.to_string(),
             board: board,
+            mcu: mcu,
             llvm_target: llvm_target.to_string(),
             core_path: core_path,
             variant_path: variant_path,
-            library_paths: library_paths,
+            library_paths: library_paths.into_iter().collect(),
             c_system_includes: c_system_includes,
             cpp_system_includes: cpp_system_includes,
+            include_dirs: options.include_dirs,
+            defines: defines,
             c_compiler: c_compiler,
             cpp_compiler: cpp_compiler,
             assembler: assembler,
-            archiver: archiver
+            archiver: archiver,
+            variant_includes_first: options.variant_includes_first,
+            prefs: expanded_prefs,
+            link: options.link,
+            rebuild_core_file: options.rebuild_core_file,
+            out_dir: PathBuf::new(),
+            flags: Flags::default()
         };
 
         serde_json::to_string(&config).chain_err(|| "Unable to serialize configuration")
     }
 
+    #[doc(hidden)]
+    pub fn serialize_flags(options: &Options) -> Result<String> {
+        let flags = Flags {
+            verbose: options.verbose,
+            quiet: options.quiet,
+            dump_objects: options.dump_objects,
+            timings_file: options.timings_file.clone()
+        };
+        serde_json::to_string(&flags).chain_err(|| "Unable to serialize flags")
+    }
+
     pub fn new() -> Result<Config> {
-        env::var("CARGUINO_CONFIG").chain_err(|| {
+        let config = env::var("CARGUINO_CONFIG").chain_err(|| {
             "Could not read $CARGUINO_CONFIG variable (is carguino running?)"
-        }).and_then(|config_var| {
-            serde_json::from_str(&config_var).chain_err(|| "Unable to deserialize configuration")
-        })
+        })?;
+        let out_dir = env::var_os("OUT_DIR").map_or_else(|| {
+            Err("Could not read $OUT_DIR variable (is this a build script?)")
+        }, Ok)?;
+        // Without flags the build scripts are simply quiet about what they do.
+        let flags = env::var("CARGUINO_FLAGS").ok();
+        Config::from_serialized(&config, flags.as_ref().map(String::as_str), out_dir)
+    }
+
+    #[doc(hidden)]
+    pub fn from_serialized<P: Into<PathBuf>>(config: &str, flags: Option<&str>, out_dir: P) -> Result<Config> {
+        let config = serde_json::from_str::<serde_json::Value>(config).chain_err(|| {
+            "Unable to deserialize configuration"
+        })?;
+        let version = config["version"].as_u64();
+        if version != Some(CONFIG_VERSION as u64) {
+            bail!(ErrorKind::VersionMismatch(CONFIG_VERSION, version));
+        }
+        let mut config = serde_json::from_value::<Config>(config).chain_err(|| "Unable to deserialize configuration")?;
+        config.out_dir = out_dir.into();
+        if let Some(flags) = flags {
+            config.flags = serde_json::from_str(flags).chain_err(|| "Unable to deserialize flags")?;
+        }
+        Ok(config)
     }
 
     pub fn core(&self) -> &str {
@@ -102,102 +238,333 @@ impl Config {
         &self.arch
     }
 
+    pub fn pref(&self, key: &str) -> Option<String> {
+        self.prefs.get(key).cloned()
+    }
+
+    fn fingerprint(&self) -> String {
+        // Only settings that affect the compiled objects are part of the fingerprint.
+        let settings = (&self.core, &self.arch, &self.board, &self.mcu, &self.llvm_target, &self.core_path, &self.variant_path,
+                        self.variant_includes_first, &self.include_dirs, &self.defines, &self.c_compiler,
+                        &self.cpp_compiler, &self.assembler, &self.archiver);
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(&settings).unwrap().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
     fn base_includes(&self) -> Vec<PathBuf> {
-        vec![self.core_path.clone(), self.variant_path.clone()]
+        // arduino-builder puts the core first, but on some platforms variant headers override core headers. The
+        // directories given to carguino come after both.
+        let core_path = Some(self.core_path.clone());
+        let (first, second) = if self.variant_includes_first {
+            (self.variant_path.clone(), core_path)
+        } else {
+            (core_path, self.variant_path.clone())
+        };
+        first.into_iter().chain(second).chain(self.include_dirs.iter().cloned()).collect()
     }
 
-    fn compile(&self, source_file: &Path, object_file: &Path, include_dirs: &[PathBuf]) -> Result<()> {
-        let recipe = match source_file {
-            path if is_c_source(path) => &self.c_compiler,
-            path if is_cpp_source(path) => &self.cpp_compiler,
-            path if is_asm_source(path) => &self.assembler,
-            _ => unreachable!()
+    fn compile(&self, source_file: &Path, language: Language, object_file: &Path, include_dirs: &[PathBuf],
+               flags: &[String]) -> Result<()> {
+        let (name, recipe) = match language {
+            Language::C => ("c.o", Some(&self.c_compiler)),
+            Language::Cpp => ("cpp.o", Some(&self.cpp_compiler)),
+            Language::Assembly => ("S.o", self.assembler.as_ref())
         };
+        let recipe = recipe.map_or_else(|| Err(ErrorKind::MissingRecipe(format!("recipe.{}.pattern", name))), Ok)?;
+        let command = recipe.command();
+        if !command_exists(&command) {
+            bail!("Compiler '{}' does not exist (has the Arduino installation been moved?)", command.display());
+        }
         fs::create_dir_all(object_file.parent().unwrap()).chain_err(|| "Unable to create directory")?;
 
-        let includes = self.base_includes().iter().chain(include_dirs).fold(String::new(), |acc, include| {
-            format!(r#"{} "-I{}""#, acc, include.display())
-        });
+        // Without a section per function and object, the `--gc-sections` that rustc passes to GNU linkers can not
+        // remove unused code, as the Arduino IDE does.
+        let section_flags: &[&str] = if name == "S.o" { &[] } else { SECTION_FLAGS };
+        let missing_section_flags = section_flags.iter().filter(|flag| !recipe.0.contains(*flag)).map(|flag| flag.to_string());
+
+        let include_flags = self.base_includes().iter().chain(include_dirs).map(|include| {
+            format!("-I{}", include.display())
+        }).collect::<Vec<_>>();
+        let includes = missing_section_flags.chain(flags.iter().cloned()).chain(include_flags).collect();
 
-        recipe.run(RecipeParams {
+        self.run_recipe(name, recipe, source_file, RecipeParams {
             source_file: source_file.to_string_lossy().to_string(),
             object_file: object_file.to_string_lossy().to_string(),
             includes: includes,
+            defines: self.defines.clone(),
             .. RecipeParams::default()
-        }).map(|_| ())
+        })
+    }
+
+    fn archiver(&self) -> Recipe {
+        let archiver = match self.archiver {
+            Some(ref archiver) => archiver,
+            None => {
+                println!("cargo:warning=Platform does not define an archiver recipe; using 'llvm-ar'");
+                return Recipe::default_archiver();
+            }
+        };
+        let command = archiver.command();
+        if can_spawn(&command) {
+            archiver.clone()
+        } else {
+            println!("cargo:warning=Unable to run archiver '{}'; falling back to 'llvm-ar'", command.display());
+            archiver.with_command("llvm-ar")
+        }
     }
 
-    fn archive(&self, object_file: &Path, archive_file: &Path) -> Result<()> {
+    fn archive(&self, archiver: &Recipe, object_files: &[PathBuf], archive_file: &Path) -> Result<()> {
         fs::create_dir_all(archive_file.parent().unwrap()).chain_err(|| "Unable to create directory")?;
 
-        self.archiver.run(RecipeParams {
+        let params = |object_file: &Path| RecipeParams {
             object_file: object_file.to_string_lossy().to_string(),
+            object_files: object_files.iter().map(|object_file| object_file.to_string_lossy().to_string()).collect(),
             archive_file: archive_file.to_string_lossy().to_string(),
+            archive_file_name: archive_file.file_name().unwrap().to_string_lossy().to_string(),
             .. RecipeParams::default()
-        }).map(|_| ())
+        };
+
+        // Recipes that accept all object files at once are only run once.
+        if archiver.has_param("object_files") {
+            self.run_recipe("ar", archiver, archive_file, params(Path::new("")))
+        } else {
+            for object_file in object_files {
+                self.run_recipe("ar", archiver, object_file, params(object_file))?;
+            }
+            Ok(())
+        }
     }
 
-    fn generate_bindings(&self, builder: BindgenBuilder, header_file: &Path, include_dirs: &[PathBuf], target_dir: &Path) -> Result<()> {
-        let builder = builder.header(header_file.to_string_lossy())
-                             .use_core()
-                             .clang_arg("-target").clang_arg(self.llvm_target.as_str());
+    fn run_recipe(&self, name: &str, recipe: &Recipe, file: &Path, params: RecipeParams) -> Result<()> {
+        let start = Instant::now();
+        recipe.run(params, !self.flags.quiet)?;
+
+        if let Some(ref timings_file) = self.flags.timings_file {
+            let elapsed = start.elapsed();
+            let timing = RecipeTiming {
+                recipe: name,
+                file: file,
+                duration: elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
+            };
+            let mut timings = OpenOptions::new().create(true).append(true).open(timings_file).chain_err(|| {
+                "Unable to open timings file"
+            })?;
+            writeln!(timings, "{}", serde_json::to_string(&timing).unwrap()).chain_err(|| "Unable to write timings file")?;
+        }
+
+        Ok(())
+    }
+
+    fn generate_bindings(&self, bindgen: Bindgen, header_file: &Path) -> Result<()> {
+        let clang_args = self.clang_args(&bindgen, header_file)?;
+        if self.flags.verbose {
+            println!("cargo:warning=Generating bindings for '{}' with clang arguments: {}",
+                     header_file.display(), clang_args.join(" "));
+        }
+
+        let builder = clang_args.iter().fold(bindgen.options.header(header_file.to_string_lossy()).use_core(), |builder, arg| {
+            builder.clang_arg(arg.as_str())
+        });
 
+        let bindings = match builder.generate() {
+            Ok(bindings) => bindings,
+            Err(()) => {
+                bail!("Unable to generate bindings for '{}'\nclang arguments: {}\n{}", header_file.display(),
+                      clang_args.join(" "), clang_diagnostics(header_file, &clang_args).trim())
+            }
+        };
+        let bindings_file = bindgen.target_dir.join(header_file.with_extension("rs").file_name().unwrap());
+        bindings.write_to_file(bindings_file).chain_err(|| "Unable to write bindings")
+    }
+
+    fn clang_args(&self, bindgen: &Bindgen, header_file: &Path) -> Result<Vec<String>> {
         let (compiler, system_includes) = match header_file {
             path if is_c_header(path) => (&self.c_compiler, &self.c_system_includes),
             path if is_cpp_header(path) => (&self.cpp_compiler, &self.cpp_system_includes),
             _ => bail!("Unknown header extension")
         };
 
-        let builder = system_includes.iter().fold(builder, |builder, include| {
-            builder.clang_arg("-isystem").clang_arg(include.to_string_lossy())
-        });
+        let clang_target = bindgen.clang_target.clone().unwrap_or_else(|| self.llvm_target.clone());
+        let mut clang_args = vec!["-target".to_string(), clang_target];
 
-        let include_dirs = self.base_includes().iter().chain(include_dirs).fold(String::new(), |acc, include| {
-            format!(r#"{} "-I{}""#, acc, include.display())
-        });
+        for include in system_includes {
+            clang_args.push("-isystem".to_string());
+            clang_args.push(include.to_string_lossy().to_string());
+        }
+
+        let include_dirs = self.base_includes().iter().chain(&bindgen.include_dirs).map(|include| {
+            format!("-I{}", include.display())
+        }).collect();
 
         let (_, args) = compiler.substitute(RecipeParams {
             includes: include_dirs,
+            defines: self.defines.clone(),
             .. RecipeParams::default()
         });
 
-        let builder = args.iter().fold(builder, |builder, arg| match arg.as_str() {
-            arg if arg.starts_with("-std=") ||
-                   arg.starts_with("-m") ||
-                   arg.starts_with("-I") ||
-                   arg.starts_with("-D") => builder.clang_arg(arg),
-            _ => builder
-        });
-
-        let bindings = builder.generate().map_err(|_| "Unable to generate bindings")?;
-        let bindings_file = target_dir.join(header_file.with_extension("rs").file_name().unwrap());
-        bindings.write_to_file(bindings_file).chain_err(|| "Unable to write bindings")
+        // Paths that are already system includes are not added again, as clang warns when a system include
+        // directory is changed into a regular one.
+        clang_args.extend(args.into_iter().filter(|arg| {
+            arg.starts_with("-std=") ||
+            arg.starts_with("-m") ||
+            (arg.starts_with("-I") && !system_includes.iter().any(|include| Path::new(&arg[2..]) == include)) ||
+            arg.starts_with("-D")
+        }));
+        clang_args.extend(bindgen.extra_clang_args.iter().cloned());
+        Ok(clang_args)
     }
 
     pub fn builder(&self) -> Builder {
         Builder {
             config: self,
             sources: Vec::new(),
+            languages: HashMap::new(),
             include_dirs: Vec::new(),
-            target_dir: env::var_os("OUT_DIR").map(PathBuf::from).unwrap()
+            target_dir: self.out_dir.clone(),
+            object_dir: None,
+            flags: Vec::new(),
+            define_set: None,
+            core: false
+        }
+    }
+
+    pub fn link_library(&self, name: &str) -> Result<Vec<PathBuf>> {
+        self.link_libraries(&[name])
+    }
+
+    // Every library is compiled with the include directories of all of them, so that libraries can use each other.
+    // GCC only resolves symbols from static libraries that are linked after the code using them, so the libraries
+    // are linked in the given order: each library before the ones it depends on, and the core last.
+    pub fn link_libraries(&self, names: &[&str]) -> Result<Vec<PathBuf>> {
+        let libraries = names.iter().map(|name| self.library(name)).collect::<Result<Vec<_>>>()?;
+        let include_dirs = libraries.iter().flat_map(|library| library.include_dirs()).collect::<Vec<_>>();
+        for library in &libraries {
+            self.build_library(library, &include_dirs)?;
+        }
+        Ok(include_dirs)
+    }
+
+    fn library<'b>(&'b self, name: &'b str) -> Result<Library<'b>> {
+        let library_path = self.library_paths.get(name).map_or_else(|| {
+            Err(format!("Platform library '{}' not found (available: {})", name,
+                        self.library_paths.keys().cloned().collect::<Vec<_>>().join(", ")))
+        }, Ok)?;
+
+        let properties_file = library_path.join("library.properties");
+        let properties = if properties_file.is_file() {
+            LibraryProperties::parse_file(&properties_file)?
+        } else {
+            LibraryProperties::default()
+        };
+
+        // Only libraries in the 1.5 format (with a `library.properties` file) are compiled recursively from `src`.
+        let source_dir = library_path.join("src");
+        let recursive = properties_file.is_file() && source_dir.is_dir();
+
+        Ok(Library {
+            name: name,
+            path: library_path,
+            properties: properties,
+            source_dir: source_dir,
+            recursive: recursive
+        })
+    }
+
+    fn build_library(&self, library: &Library, include_dirs: &[PathBuf]) -> Result<()> {
+        emit_ldflags(library.properties.ldflags());
+
+        if library.properties.precompiled() != Precompiled::No {
+            let archive_dir = library.source_dir.join(&self.mcu);
+            let archives = find_archives(&archive_dir);
+            if archives.is_empty() {
+                println!("cargo:warning=No precompiled archive of '{}' for '{}' found; compiling sources instead",
+                         library.name, self.mcu);
+            } else {
+                println!("cargo:rustc-link-search=native={}", archive_dir.display());
+                for archive in archives {
+                    println!("cargo:rustc-link-lib=static={}", archive);
+                }
+                if library.properties.precompiled() == Precompiled::Full {
+                    return Ok(());
+                }
+            }
         }
+
+        let mut builder = self.builder();
+        if library.recursive {
+            collect_sources(&library.source_dir, true, &mut builder.sources);
+        } else {
+            collect_sources(library.path, false, &mut builder.sources);
+            let utility_dir = library.path.join("utility");
+            if utility_dir.is_dir() {
+                collect_sources(&utility_dir, false, &mut builder.sources);
+                builder.include_dirs.push(utility_dir);
+            }
+        }
+        builder.include_dirs.extend(include_dirs.iter().cloned());
+        builder.build(library_name(library.name))
     }
 
     pub fn bindgen(&self) -> Bindgen {
         Bindgen {
             config: self,
             include_dirs: Vec::new(),
-            target_dir: env::var_os("OUT_DIR").map(PathBuf::from).unwrap(),
+            target_dir: self.out_dir.clone(),
+            clang_target: None,
+            extra_clang_args: Vec::new(),
             options: bindgen::builder()
         }
     }
 }
 
+struct Library<'a> {
+    name: &'a str,
+    path: &'a Path,
+    properties: LibraryProperties,
+    source_dir: PathBuf,
+    recursive: bool
+}
+
+impl<'a> Library<'a> {
+    fn include_dirs(&self) -> Vec<PathBuf> {
+        vec![if self.recursive { self.source_dir.clone() } else { self.path.to_path_buf() }]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    C,
+    Cpp,
+    Assembly
+}
+
+impl Language {
+    fn of(path: &Path) -> Option<Language> {
+        match path {
+            path if is_c_source(path) => Some(Language::C),
+            path if is_cpp_source(path) => Some(Language::Cpp),
+            path if is_asm_source(path) => Some(Language::Assembly),
+            _ => None
+        }
+    }
+}
+
+pub struct Outputs {
+    pub archive_file: PathBuf,
+    pub object_files: Vec<PathBuf>
+}
+
 pub struct Builder<'a> {
     config: &'a Config,
     sources: Vec<PathBuf>,
+    languages: HashMap<PathBuf, Language>,
     include_dirs: Vec<PathBuf>,
-    target_dir: PathBuf
+    target_dir: PathBuf,
+    object_dir: Option<PathBuf>,
+    flags: Vec<String>,
+    define_set: Option<String>,
+    core: bool
 }
 
 impl<'a> Builder<'a> {
@@ -206,36 +573,200 @@ impl<'a> Builder<'a> {
         self
     }
 
+    // Compiles the source as the given language, for libraries whose sources do not have the usual extensions.
+    pub fn source_as<P: Into<PathBuf>>(mut self, source: P, language: Language) -> Builder<'a> {
+        let source = source.into();
+        self.languages.insert(source.clone(), language);
+        self.sources.push(source);
+        self
+    }
+
     pub fn core_sources(mut self) -> Builder<'a> {
+        self.core = true;
         collect_sources(&self.config.core_path, true, &mut self.sources);
-        collect_sources(&self.config.variant_path, true, &mut self.sources);
+        if let Some(ref variant_path) = self.config.variant_path {
+            collect_sources(variant_path, true, &mut self.sources);
+        }
         self
     }
 
+    // Reads the sources to compile from a file with one path per line (relative to the file), to be used
+    // instead of `core_sources` when the recursive scan picks up too much. Empty lines and lines starting
+    // with `#` are skipped.
+    pub fn sources_from_manifest<P: AsRef<Path>>(mut self, path: P) -> Result<Builder<'a>> {
+        let path = path.as_ref();
+        let file = File::open(path).chain_err(|| format!("Unable to open source manifest '{}'", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for line in BufReader::new(file).lines() {
+            let line = line.chain_err(|| format!("Unable to read source manifest '{}'", path.display()))?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                self.sources.push(base_dir.join(line));
+            }
+        }
+        println!("cargo:rerun-if-changed={}", path.display());
+        Ok(self)
+    }
+
     pub fn include_dir<P: Into<PathBuf>>(mut self, include_dir: P) -> Builder<'a> {
         self.include_dirs.push(include_dir.into());
         self
     }
 
+    pub fn define<'b, V: Into<Option<&'b str>>>(mut self, key: &str, value: V) -> Builder<'a> {
+        self.flags.push(match value.into() {
+            Some(value) => format!("-D{}={}", key, value),
+            None => format!("-D{}", key)
+        });
+        self
+    }
+
+    pub fn undef(mut self, key: &str) -> Builder<'a> {
+        self.flags.push(format!("-U{}", key));
+        self
+    }
+
+    // Builds that compile the same sources with different defines (e.g. a test harness and the firmware) name a set
+    // of defines, which gets its own archive and objects, keyed on the defines applied to it.
+    pub fn define_set<S: Into<String>>(mut self, name: S) -> Builder<'a> {
+        self.define_set = Some(name.into());
+        self
+    }
+
     pub fn target_dir<P: Into<PathBuf>>(mut self, target_dir: P) -> Builder<'a> {
         self.target_dir = target_dir.into();
         self
     }
 
+    pub fn object_dir<P: Into<PathBuf>>(mut self, object_dir: P) -> Builder<'a> {
+        self.object_dir = Some(object_dir.into());
+        self
+    }
+
     pub fn build<S: Into<String>>(self, lib_name: S) -> Result<()> {
-        let lib_name = lib_name.into();
+        self.build_with_outputs(lib_name).map(|_| ())
+    }
+
+    // Like `build`, but also returns the archive and the object files in it (none when nothing is linked).
+    pub fn build_with_outputs<S: Into<String>>(self, lib_name: S) -> Result<Outputs> {
+        let lib_name = match self.define_set {
+            Some(ref define_set) => {
+                let mut hasher = DefaultHasher::new();
+                self.flags.hash(&mut hasher);
+                format!("{}-{}-{:016x}", lib_name.into(), define_set, hasher.finish())
+            }
+            None => lib_name.into()
+        };
+        let archive_file = self.target_dir.join(format!("lib{}.a", lib_name));
+
+        // Nothing is linked when only checking or documenting, so the sources do not need to be compiled.
+        if !self.config.link {
+            for source_file in &self.sources {
+                println!("cargo:rerun-if-changed={}", source_file.display());
+            }
+            println!("cargo:rerun-if-env-changed=CARGUINO_CONFIG");
+            return Ok(Outputs {
+                archive_file: archive_file,
+                object_files: Vec::new()
+            });
+        }
+
+        let archiver = self.config.archiver();
+        let object_dir = self.object_dir.clone().unwrap_or_else(|| self.target_dir.join("obj").join(&lib_name));
+
+        // Objects compiled with a different configuration (e.g. for another board) can not be reused.
+        let fingerprint_file = object_dir.join(".fingerprint");
+        let fingerprint = format!("{} {}", self.config.fingerprint(), self.flags.join(" "));
+        let mut fresh_config = read_fingerprint(&fingerprint_file).map_or(false, |previous| previous == fingerprint);
+
+        // The board stamp makes sure a core built for one board is never linked for another, even when their settings
+        // happen to have the same fingerprint.
+        let board_file = object_dir.join(".board");
+        if let Some(previous_board) = read_fingerprint(&board_file) {
+            if previous_board != self.config.board_id {
+                println!("cargo:warning=Rebuilding '{}' for {}; it was built for {}", lib_name, self.config.board_id,
+                         previous_board);
+                fresh_config = false;
+            }
+        }
+        // A request is only handled once, so the next build without `--rebuild-core` reuses the objects again.
+        let rebuild_core_file = object_dir.join(".rebuild-core");
+        let rebuild_request = match self.config.rebuild_core_file {
+            Some(ref request_file) if self.core => {
+                println!("cargo:rerun-if-changed={}", request_file.display());
+                read_fingerprint(request_file).unwrap_or_default()
+            }
+            _ => String::new()
+        };
+        if !rebuild_request.is_empty() && read_fingerprint(&rebuild_core_file).as_ref() != Some(&rebuild_request) {
+            println!("cargo:warning=Rebuilding '{}' as requested by --rebuild-core", lib_name);
+            fresh_config = false;
+        }
+        if !fresh_config && archive_file.is_file() {
+            fs::remove_file(&archive_file).chain_err(|| "Unable to remove stale archive")?;
+        }
+        let archive_exists = archive_file.is_file();
 
+        let mut object_files = Vec::new();
+        let mut archived_files = Vec::new();
         for source_file in self.sources {
-            let object_file = self.target_dir.join(&lib_name).join(source_file.file_name().unwrap()).with_extension("o");
-            self.config.compile(&source_file, &object_file, &self.include_dirs)?;
-            self.config.archive(&object_file, &self.target_dir.join(format!("lib{}.a", lib_name)))?;
-            //println!("cargo:rerun-if-changed={}", source_file.display());
+            let language = match self.languages.get(&source_file).cloned().or_else(|| Language::of(&source_file)) {
+                Some(language) => language,
+                None => bail!("Unknown language of source '{}'", source_file.display())
+            };
+            if language == Language::Assembly && self.config.assembler.is_none() {
+                println!("cargo:warning=Platform does not define an assembler recipe; skipping '{}'", source_file.display());
+                continue;
+            }
+            let object_file = object_dir.join(source_file.file_name().unwrap()).with_extension("o");
+            let stale_reason = stale_reason(fresh_config, &object_file, &source_file);
+            if self.config.flags.verbose {
+                println!("{}", compile_status(&source_file, stale_reason));
+            }
+            let compile = stale_reason.is_some();
+            if compile {
+                self.config.compile(&source_file, language, &object_file, &self.include_dirs, &self.flags)?;
+            }
+            if compile || !archive_exists {
+                object_files.push(object_file.clone());
+            }
+            archived_files.push(object_file);
+            println!("cargo:rerun-if-changed={}", source_file.display());
+        }
+
+        if !object_files.is_empty() {
+            self.config.archive(&archiver, &object_files, &archive_file)?;
         }
 
+        fs::create_dir_all(&object_dir).chain_err(|| "Unable to create directory")?;
+        File::create(&fingerprint_file).and_then(|mut file| {
+            file.write_all(fingerprint.as_bytes())
+        }).chain_err(|| "Unable to write fingerprint")?;
+        File::create(&board_file).and_then(|mut file| {
+            file.write_all(self.config.board_id.as_bytes())
+        }).chain_err(|| "Unable to write board stamp")?;
+        if !rebuild_request.is_empty() {
+            File::create(&rebuild_core_file).and_then(|mut file| {
+                file.write_all(rebuild_request.as_bytes())
+            }).chain_err(|| "Unable to write rebuild request")?;
+        }
+
+        println!("cargo:rerun-if-env-changed=CARGUINO_CONFIG");
         println!("cargo:rustc-link-search=native={}", self.target_dir.display());
         println!("cargo:rustc-link-lib=static={}", lib_name);
 
-        Ok(())
+        // Build script output is only shown for warnings.
+        if self.config.flags.dump_objects {
+            println!("cargo:warning=Archive '{}' contains {} object files:", archive_file.display(), archived_files.len());
+            for object_file in &archived_files {
+                println!("cargo:warning=  {}", object_file.display());
+            }
+        }
+
+        Ok(Outputs {
+            archive_file: archive_file,
+            object_files: archived_files
+        })
     }
 }
 
@@ -243,6 +774,8 @@ pub struct Bindgen<'a> {
     config: &'a Config,
     include_dirs: Vec<PathBuf>,
     target_dir: PathBuf,
+    clang_target: Option<String>,
+    extra_clang_args: Vec<String>,
     options: BindgenBuilder
 }
 
@@ -257,6 +790,18 @@ impl<'a> Bindgen<'a> {
         self
     }
 
+    // Overrides the clang target triple, which defaults to the LLVM target of the board's target specification.
+    pub fn clang_target<S: Into<String>>(mut self, clang_target: S) -> Bindgen<'a> {
+        self.clang_target = Some(clang_target.into());
+        self
+    }
+
+    // Appends arguments after the ones derived from the board's compiler recipe.
+    pub fn extra_clang_args<I: IntoIterator<Item = String>>(mut self, args: I) -> Bindgen<'a> {
+        self.extra_clang_args.extend(args);
+        self
+    }
+
     pub fn options<F: FnOnce(BindgenBuilder) -> BindgenBuilder>(mut self, f: F) -> Bindgen<'a> {
         self.options = f(self.options);
         self
@@ -264,8 +809,9 @@ impl<'a> Bindgen<'a> {
 
     pub fn generate<P: Into<PathBuf>>(self, header_file: P) -> Result<()> {
         let header_file = header_file.into();
-        self.config.generate_bindings(self.options, &header_file, &self.include_dirs, &self.target_dir)?;
-        //println!("cargo:rerun-if-changed={}", header_file.display());
+        self.config.generate_bindings(self, &header_file)?;
+        println!("cargo:rerun-if-changed={}", header_file.display());
+        println!("cargo:rerun-if-env-changed=CARGUINO_CONFIG");
 
         Ok(())
     }
@@ -277,8 +823,18 @@ impl<'a> Bindgen<'a> {
 struct Recipe(String);
 
 impl Recipe {
-    fn from_prefs(prefs: &Preferences, name: &str) -> Recipe {
-        Recipe(prefs.get::<String>(&format!("recipe.{}.pattern", name)).unwrap())
+    fn from_prefs(prefs: &Preferences, name: &str) -> Result<Recipe> {
+        Recipe::optional(prefs, name).map_or_else(|| {
+            Err(ErrorKind::MissingRecipe(format!("recipe.{}.pattern", name)).into())
+        }, Ok)
+    }
+
+    fn optional(prefs: &Preferences, name: &str) -> Option<Recipe> {
+        prefs.get::<String>(&format!("recipe.{}.pattern", name)).map(Recipe)
+    }
+
+    fn default_archiver() -> Recipe {
+        Recipe(r#""llvm-ar" rcs "%archive_file" %object_files"#.to_string())
     }
 
     fn command(&self) -> PathBuf {
@@ -286,19 +842,37 @@ impl Recipe {
         command_path
     }
 
-    fn substitute(&self, params: RecipeParams) -> (PathBuf, Vec<String>) {
+    fn has_param(&self, name: &str) -> bool {
+        let (_, args) = split_command_line(&self.0);
+        args.iter().any(|arg| *arg == format!("%{}", name))
+    }
+
+    fn with_command<P: AsRef<Path>>(&self, command: P) -> Recipe {
         lazy_static! {
-            static ref REGEX: Regex = Regex::new(r#"%(\w+)"#).unwrap();
+            static ref REGEX: Regex = Regex::new(r#"^\s*(?:'.*?'|".*?"|\S+)"#).unwrap();
         }
 
-        let expanded = REGEX.replace_all(&self.0, |captures: &Captures| {
-            params.substitute(&captures[1])
-        });
+        let args = REGEX.find(&self.0).map_or("", |program| &self.0[program.end()..]);
+        Recipe(format!(r#""{}"{}"#, command.as_ref().display(), args))
+    }
 
-        split_command_line(&expanded)
+    fn substitute(&self, params: RecipeParams) -> (PathBuf, Vec<String>) {
+        // Substitute the parameters after splitting the command line, so that parameter values containing
+        // spaces (e.g. paths) are never split into multiple arguments.
+        let (command_path, args) = split_command_line(&self.0);
+        let args = args.iter().flat_map(|arg| params.substitute(arg)).collect::<Vec<_>>();
+
+        // Missing defines are added in front of the include directories, where recipes usually have them.
+        let missing_defines = params.defines.iter().filter(|define| {
+            !args.iter().any(|arg| define_name(arg) == define_name(define))
+        }).cloned().collect::<Vec<_>>();
+        let position = args.iter().position(|arg| arg.starts_with("-I")).unwrap_or(args.len());
+        let args = args[..position].iter().cloned().chain(missing_defines).chain(args[position..].iter().cloned()).collect();
+
+        (command_path, args)
     }
 
-    fn run(&self, params: RecipeParams) -> Result<Output> {
+    fn run(&self, params: RecipeParams, forward_warnings: bool) -> Result<Output> {
         let (command_path, args) = self.substitute(params);
 
         let mut command = Command::new(&command_path);
@@ -308,10 +882,12 @@ impl Recipe {
 
         let output = command.output().chain_err(|| "Unable to start process")?;
         if output.status.success() {
-            {
-                let reader = BufReader::new(Cursor::new(&output.stderr));
-                for warning in reader.lines().filter_map(|line| line.ok()).filter(|line| line.contains("warning:")) {
-                    println!("cargo:warning={}", warning);
+            if forward_warnings {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                for diagnostic in diagnostics(&stderr).iter().filter(|lines| lines.iter().any(|line| line.contains("warning:"))) {
+                    for line in diagnostic {
+                        println!("cargo:warning={}", line);
+                    }
                 }
             }
             Ok(output)
@@ -323,28 +899,101 @@ impl Recipe {
     }
 }
 
+#[derive(Serialize)]
+struct RecipeTiming<'a> {
+    recipe: &'a str,
+    file: &'a Path,
+    duration: f64
+}
+
 #[derive(Default)]
 struct RecipeParams {
     source_file: String,
     object_file: String,
-    object_files: String,
+    object_files: Vec<String>,
     archive_file: String,
-    includes: String
+    archive_file_name: String,
+    includes: Vec<String>,
+    defines: Vec<String>
 }
 
 impl RecipeParams {
-    fn substitute(&self, pattern: &str) -> String {
+    fn substitute(&self, arg: &str) -> Vec<String> {
+        lazy_static! {
+            static ref REGEX: Regex = Regex::new(r#"%(\w+)"#).unwrap();
+        }
+
+        match arg {
+            "%object_files" => self.object_files.clone(),
+            "%includes" => self.includes.clone(),
+            arg => {
+                vec![REGEX.replace_all(arg, |captures: &Captures| self.substitute_one(&captures[1])).into_owned()]
+            }
+        }
+    }
+
+    fn substitute_one(&self, pattern: &str) -> String {
         match pattern {
             "source_file" => self.source_file.clone(),
             "object_file" => self.object_file.clone(),
-            "object_files" => self.object_files.clone(),
+            "object_files" => self.object_files.join(" "),
             "archive_file" => self.archive_file.clone(),
-            "includes" => self.includes.clone(),
+            "archive_file_name" => self.archive_file_name.clone(),
+            "includes" => self.includes.join(" "),
             text => text.to_string()
         }
     }
 }
 
+// GCC prints each diagnostic as a `file:line:column: warning: ...` line, preceded by context such as the function or
+// include chain, and followed by indented source and caret lines and by any notes.
+fn diagnostics(stderr: &str) -> Vec<Vec<&str>> {
+    let mut diagnostics = Vec::new();
+    let mut current = Vec::new();
+    let mut has_message = false;
+    for line in stderr.lines() {
+        let is_continuation = line.starts_with(char::is_whitespace) || line.contains(": note:");
+        let is_context = line.starts_with("In file included from") || line.contains(": In ");
+        if !is_continuation && has_message {
+            diagnostics.push(mem::replace(&mut current, Vec::new()));
+            has_message = false;
+        }
+        has_message = has_message || (!is_continuation && !is_context);
+        current.push(line);
+    }
+    if !current.is_empty() {
+        diagnostics.push(current);
+    }
+    diagnostics
+}
+
+fn define_name(arg: &str) -> Option<&str> {
+    if arg.starts_with("-D") {
+        arg[2..].split('=').next()
+    } else {
+        None
+    }
+}
+
+fn validate_recipe_override(key: &str, pattern: &str) -> Result<()> {
+    if !key.starts_with("recipe.") || !key.ends_with(".pattern") {
+        bail!("Recipe override '{}' does not name a recipe (expected 'recipe.<name>.pattern')", key);
+    }
+
+    // Each group lists alternative placeholders, of which at least one is required.
+    let required: &[&[&str]] = match key {
+        "recipe.c.o.pattern" | "recipe.cpp.o.pattern" | "recipe.S.o.pattern" => &[&["{source_file}"], &["{object_file}"]],
+        "recipe.ar.pattern" => &[&["{archive_file_path}", "{build.path}/{archive_file}"], &["{object_file}", "{object_files}"]],
+        _ => &[]
+    };
+    for placeholders in required {
+        if !placeholders.iter().any(|placeholder| pattern.contains(placeholder)) {
+            bail!("Recipe override '{}' is missing the placeholder {}", key, placeholders.join(" or "));
+        }
+    }
+    Ok(())
+}
+
 pub fn split_command_line(line: &str) -> (PathBuf, Vec<String>) {
     lazy_static! {
         static ref REGEX: Regex = Regex::new(r#"\s*(?:'(.*?)')|(?:"(.*?)")|(\S+)"#).unwrap();
@@ -363,6 +1012,39 @@ pub fn split_command_line(line: &str) -> (PathBuf, Vec<String>) {
     (command, args)
 }
 
+pub fn command_exists(command: &Path) -> bool {
+    !command.is_absolute() || command.is_file() || command.with_extension("exe").is_file()
+}
+
+fn emit_ldflags(ldflags: &[String]) {
+    for flag in ldflags {
+        if flag.starts_with("-l") {
+            println!("cargo:rustc-link-lib={}", &flag[2..]);
+        } else if flag.starts_with("-L") {
+            println!("cargo:rustc-link-search=native={}", &flag[2..]);
+        } else {
+            println!("cargo:warning=Unsupported linker flag '{}' ignored", flag);
+        }
+    }
+}
+
+fn find_archives(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir).map(|entries| {
+        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with("lib") && file_name.ends_with(".a") {
+                Some(file_name[3..file_name.len() - 2].to_string())
+            } else {
+                None
+            }
+        }).collect()
+    }).unwrap_or_default()
+}
+
+fn library_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect()
+}
+
 fn collect_sources(dir: &Path, recursive: bool, sources: &mut Vec<PathBuf>) {
     for entry in fs::read_dir(dir).unwrap() {
         let path = entry.unwrap().path();
@@ -376,6 +1058,89 @@ fn collect_sources(dir: &Path, recursive: bool, sources: &mut Vec<PathBuf>) {
     }
 }
 
+fn clang_diagnostics(header_file: &Path, clang_args: &[String]) -> String {
+    Command::new("clang").arg("-fsyntax-only")
+                         .args(clang_args)
+                         .arg(header_file)
+                         .output()
+                         .map(|output| String::from_utf8_lossy(&output.stderr).to_string())
+                         .unwrap_or_default()
+}
+
+fn read_fingerprint(path: &Path) -> Option<String> {
+    File::open(path).and_then(|mut file| {
+        let mut fingerprint = String::new();
+        file.read_to_string(&mut fingerprint).map(|_| fingerprint)
+    }).ok()
+}
+
+fn stale_reason(fresh_config: bool, object_file: &Path, source_file: &Path) -> Option<&'static str> {
+    if !fresh_config {
+        Some("the configuration changed")
+    } else if !is_up_to_date(object_file, source_file) {
+        Some("the source is newer than its object")
+    } else {
+        None
+    }
+}
+
+// Build script output is only shown for warnings, so these are the only way to see what was rebuilt.
+fn compile_status(source_file: &Path, stale_reason: Option<&str>) -> String {
+    match stale_reason {
+        Some(reason) => format!("cargo:warning=Compiling '{}' ({})", source_file.display(), reason),
+        None => format!("cargo:warning=Reusing the cached object of '{}'", source_file.display())
+    }
+}
+
+fn is_up_to_date(target: &Path, source: &Path) -> bool {
+    let target_modified = fs::metadata(target).and_then(|metadata| metadata.modified());
+    let source_modified = fs::metadata(source).and_then(|metadata| metadata.modified());
+    match (target_modified, source_modified) {
+        (Ok(target_modified), Ok(source_modified)) => target_modified >= source_modified,
+        _ => false
+    }
+}
+
+fn can_spawn(command: &Path) -> bool {
+    Command::new(command).arg("--version")
+                         .stdout(Stdio::null())
+                         .stderr(Stdio::null())
+                         .status()
+                         .is_ok()
+}
+
+fn cached_system_includes(cache_dir: Option<&Path>, command: &OsStr, args: &[&str]) -> Vec<PathBuf> {
+    let cache_file = cache_dir.and_then(|cache_dir| {
+        system_includes_key(command, args).map(|key| cache_dir.join(key).with_extension("txt"))
+    });
+    if let Some(file) = cache_file.as_ref().and_then(|cache_file| File::open(cache_file).ok()) {
+        return BufReader::new(file).lines().filter_map(|line| line.ok()).map(PathBuf::from).collect();
+    }
+
+    let includes = get_system_includes(command, args);
+    if let (Some(cache_dir), Some(cache_file)) = (cache_dir, cache_file) {
+        if !includes.is_empty() {
+            // The cache is only an optimization, so failing to write it is not an error.
+            let _ = fs::create_dir_all(cache_dir).and_then(|_| File::create(&cache_file)).and_then(|mut file| {
+                for include in &includes {
+                    writeln!(file, "{}", include.display())?;
+                }
+                Ok(())
+            });
+        }
+    }
+    includes
+}
+
+// The key changes whenever the compiler binary is replaced, e.g. by updating the platform.
+fn system_includes_key(command: &OsStr, args: &[&str]) -> Option<String> {
+    fs::metadata(command).and_then(|metadata| metadata.modified()).ok().map(|modified| {
+        let mut hasher = DefaultHasher::new();
+        (command, args, modified).hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    })
+}
+
 fn get_system_includes(command: &OsStr, args: &[&str]) -> Vec<PathBuf> {
     Command::new(command).args(args).output().ok().map(|output| {
         let reader = BufReader::new(Cursor::new(&output.stderr));
@@ -425,3 +1190,109 @@ fn is_cpp_header(path: &Path) -> bool {
         _ => false
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compile_status, diagnostics, stale_reason, system_includes_key, Config, Options};
+    use prefs::Preferences;
+
+    use tempdir::TempDir;
+
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    const C_ARGS: &'static [&'static str] = &["-w", "-v", "-E", "-xc", "-"];
+    const CPP_ARGS: &'static [&'static str] = &["-w", "-v", "-E", "-xc++", "-"];
+
+    #[test]
+    fn system_includes_are_cached_per_compiler_and_language() {
+        let compiler = env::current_exe().unwrap();
+        let other_compiler = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+
+        let key = system_includes_key(compiler.as_os_str(), C_ARGS).unwrap();
+        assert_eq!(key.len(), 16);
+        assert_eq!(system_includes_key(compiler.as_os_str(), C_ARGS), Some(key.clone()));
+        assert!(system_includes_key(compiler.as_os_str(), CPP_ARGS).unwrap() != key);
+        assert!(system_includes_key(other_compiler.as_os_str(), C_ARGS).unwrap() != key);
+    }
+
+    #[test]
+    fn missing_compilers_are_not_cached() {
+        let compiler = Path::new(env!("CARGO_MANIFEST_DIR")).join("nonexistent-gcc");
+        assert_eq!(system_includes_key(compiler.as_os_str(), C_ARGS), None);
+    }
+
+    #[test]
+    fn the_reason_to_compile_a_source_is_reported() {
+        let dir = TempDir::new("carguino-build-test").unwrap();
+        let (source_file, object_file) = (dir.path().join("wiring.c"), dir.path().join("wiring.o"));
+        File::create(&source_file).unwrap();
+
+        let reason = stale_reason(true, &object_file, &source_file);
+        assert_eq!(reason, Some("the source is newer than its object"));
+        assert_eq!(compile_status(&source_file, reason),
+                   format!("cargo:warning=Compiling '{}' (the source is newer than its object)", source_file.display()));
+
+        File::create(&object_file).unwrap();
+        assert_eq!(stale_reason(false, &object_file, &source_file), Some("the configuration changed"));
+        let reason = stale_reason(true, &object_file, &source_file);
+        assert_eq!(reason, None);
+        assert_eq!(compile_status(&source_file, reason),
+                   format!("cargo:warning=Reusing the cached object of '{}'", source_file.display()));
+    }
+
+    #[test]
+    fn system_includes_are_not_passed_to_clang_again() {
+        let dir = TempDir::new("carguino-build-test").unwrap();
+        let header_file = dir.path().join("board.h");
+        File::create(&header_file).unwrap();
+
+        let prefs = Preferences::parse("build.board=AVR_UNO\nbuild.core=arduino\nbuild.core.path=/nonexistent/core\n\
+                                        build.mcu=atmega328p\n\
+                                        recipe.c.o.pattern=true -std=gnu11 -mmcu={build.mcu} {includes} -c\n\
+                                        recipe.cpp.o.pattern=true\n");
+        let options = Options {
+            board_id: "arduino:avr:uno".to_string(),
+            include_dirs: vec![PathBuf::from("/nonexistent/shared/include")],
+            .. Options::default()
+        };
+        let config = Config::serialize(prefs, "avr-atmel-none", "avr", HashMap::new(), options).unwrap();
+        let mut config = Config::from_serialized(&config, None, dir.path()).unwrap();
+        config.c_system_includes = vec![PathBuf::from("/nonexistent/core")];
+
+        let clang_args = config.clang_args(&config.bindgen(), &header_file).unwrap();
+        assert_eq!(clang_args, vec!["-target", "avr-atmel-none", "-isystem", "/nonexistent/core", "-std=gnu11",
+                                    "-mmcu=atmega328p", "-DARDUINO=10800", "-DARDUINO_AVR_UNO", "-DARDUINO_ARCH_AVR",
+                                    "-I/nonexistent/shared/include"]);
+    }
+
+    #[test]
+    fn gcc_diagnostics_are_grouped_with_their_context() {
+        let stderr = "In file included from /core/Arduino.h:32:0,\n\
+                      \x20                from /core/wiring.c:23:\n\
+                      /core/binary.h:31:0: warning: \"B1\" redefined\n\
+                      \x20#define B1 1\n\
+                      \x20^\n\
+                      /core/wiring.c: In function 'delay':\n\
+                      /core/wiring.c:106:11: warning: unused variable 'start' [-Wunused-variable]\n\
+                      \x20 uint32_t start = micros();\n\
+                      \x20          ^\n\
+                      /core/wiring.c:106:11: note: declared here\n\
+                      /core/wiring.c:120:1: error: expected ';' before '}' token\n";
+        assert_eq!(diagnostics(stderr), vec![
+            vec!["In file included from /core/Arduino.h:32:0,",
+                 "                 from /core/wiring.c:23:",
+                 "/core/binary.h:31:0: warning: \"B1\" redefined",
+                 " #define B1 1",
+                 " ^"],
+            vec!["/core/wiring.c: In function 'delay':",
+                 "/core/wiring.c:106:11: warning: unused variable 'start' [-Wunused-variable]",
+                 "  uint32_t start = micros();",
+                 "           ^",
+                 "/core/wiring.c:106:11: note: declared here"],
+            vec!["/core/wiring.c:120:1: error: expected ';' before '}' token"]
+        ]);
+    }
+}
@@ -9,5 +9,15 @@ error_chain! {
             display("Process '{}' exited with code {}", name.display(),
                     output.status.code().map_or(Cow::Borrowed("<none>"), |code| Cow::Owned(code.to_string())))
         }
+        VersionMismatch(expected: u32, found: Option<u64>) {
+            description("carguino/carguino-build version mismatch")
+            display("carguino/carguino-build version mismatch: expected configuration version {}, found {} \
+                     (update carguino or carguino-build so that they match)",
+                    expected, found.map_or(Cow::Borrowed("none"), |found| Cow::Owned(found.to_string())))
+        }
+        MissingRecipe(key: String) {
+            description("recipe missing from preferences")
+            display("Recipe '{}' missing from preferences", key)
+        }
     }
 }
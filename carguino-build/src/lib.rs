@@ -4,12 +4,15 @@ extern crate bindgen;
 extern crate regex;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
+#[cfg(test)] extern crate tempdir;
 
 pub use error::*;
-pub use config::Config;
+pub use config::{Config, Language};
+pub use library::{LibraryProperties, Precompiled};
 pub use prefs::Preferences;
 
 #[doc(hidden)]
 pub mod config;
 mod error;
+mod library;
 mod prefs;
@@ -0,0 +1,89 @@
+use Result;
+use prefs::Preferences;
+
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precompiled {
+    /// Only the sources are compiled.
+    No,
+    /// The precompiled archive is linked and the sources are compiled as well.
+    Yes,
+    /// The precompiled archive is linked instead of compiling the sources, if one exists for the target.
+    Full
+}
+
+// `includes` only tells the IDE which headers to add to a sketch, and libraries are always linked as archives, as
+// `dot_a_linkage` asks for, so neither affects the build.
+#[derive(Clone, Debug)]
+pub struct LibraryProperties {
+    ldflags: Vec<String>,
+    precompiled: Precompiled
+}
+
+impl LibraryProperties {
+    pub fn parse<S: AsRef<str>>(string: S) -> LibraryProperties {
+        LibraryProperties::from_prefs(&Preferences::parse(string))
+    }
+
+    pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<LibraryProperties> {
+        Preferences::parse_file(path).map(|prefs| LibraryProperties::from_prefs(&prefs))
+    }
+
+    fn from_prefs(prefs: &Preferences) -> LibraryProperties {
+        LibraryProperties {
+            ldflags: prefs.get_unexpanded::<String>("ldflags").map_or_else(Vec::new, |ldflags| {
+                ldflags.split_whitespace().map(String::from).collect()
+            }),
+            precompiled: match prefs.get_unexpanded::<String>("precompiled").as_ref().map(String::as_str) {
+                Some("true") => Precompiled::Yes,
+                Some("full") => Precompiled::Full,
+                _ => Precompiled::No
+            }
+        }
+    }
+
+    pub fn ldflags(&self) -> &[String] {
+        &self.ldflags
+    }
+
+    pub fn precompiled(&self) -> Precompiled {
+        self.precompiled
+    }
+}
+
+impl Default for LibraryProperties {
+    fn default() -> LibraryProperties {
+        LibraryProperties {
+            ldflags: Vec::new(),
+            precompiled: Precompiled::No
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_are_parsed() {
+        let properties = LibraryProperties::parse("name=Servo\n\
+                                                   includes=Servo.h\n\
+                                                   ldflags=-lservo  -lm\n\
+                                                   precompiled=full\n");
+        assert_eq!(properties.ldflags(), ["-lservo", "-lm"]);
+        assert_eq!(properties.precompiled(), Precompiled::Full);
+    }
+
+    #[test]
+    fn missing_properties_fall_back_to_the_defaults() {
+        let properties = LibraryProperties::parse("name=Servo\nprecompiled=false\n");
+        assert!(properties.ldflags().is_empty());
+        assert_eq!(properties.precompiled(), Precompiled::No);
+    }
+
+    #[test]
+    fn precompiled_true_links_the_archive_and_the_sources() {
+        assert_eq!(LibraryProperties::parse("precompiled=true").precompiled(), Precompiled::Yes);
+    }
+}
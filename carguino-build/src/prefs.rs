@@ -1,8 +1,13 @@
+use {Result, ResultExt};
+
 use regex::{Captures, Regex};
 
 use std::fmt::{self, Display, Formatter};
 use std::cell::{Ref, RefCell};
 use std::collections::btree_map::{self, BTreeMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Default)]
@@ -19,13 +24,10 @@ impl Preferences {
     pub fn parse<S: AsRef<str>>(string: S) -> Preferences {
         let mut prefs = BTreeMap::new();
         for line in string.as_ref().lines() {
-            // if line.starts_with("===") || !line.contains('=') {
-            //     continue;
-            // }
             let mut splits = line.splitn(2, '=');
-            let key = splits.next().unwrap();
-            let value = splits.next().unwrap();
-            prefs.insert(key.to_string(), value.to_string());
+            if let (Some(key), Some(value)) = (splits.next(), splits.next()) {
+                prefs.insert(key.to_string(), value.to_string());
+            }
         }
         Preferences {
             unexpanded: prefs,
@@ -33,6 +35,34 @@ impl Preferences {
         }
     }
 
+    pub fn try_parse<S: AsRef<str>>(string: S) -> Result<Preferences> {
+        let prefs = Preferences::parse(string);
+        if prefs.unexpanded.is_empty() {
+            bail!("No preferences found (expected lines of the form 'key=value')");
+        }
+        Ok(prefs)
+    }
+
+    pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Preferences> {
+        let path = path.as_ref();
+        let mut string = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut string)).chain_err(|| {
+            format!("Unable to read preferences file '{}'", path.display())
+        })?;
+
+        Preferences::try_parse(string).chain_err(|| format!("Malformed preferences file '{}'", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        File::create(path).and_then(|mut file| {
+            for (key, value) in &self.unexpanded {
+                writeln!(file, "{}={}", key, value)?;
+            }
+            Ok(())
+        }).chain_err(|| format!("Unable to write preferences file '{}'", path.display()))
+    }
+
     pub fn set<V: ToString>(&mut self, key: &str, value: V) {
         self.unexpanded.insert(key.to_string(), value.to_string());
         self.expanded.borrow_mut().take();
@@ -51,22 +81,30 @@ impl Preferences {
         self.expanded().get(key).and_then(|value| value.parse().ok())
     }
 
+    // Lists are stored as `<prefix>.1`, `<prefix>.2` and so on; the indices need not be contiguous.
+    pub fn get_indexed(&self, prefix: &str) -> Vec<(usize, String)> {
+        let mut values = self.unexpanded.keys().filter_map(|key| {
+            if key.starts_with(prefix) && key[prefix.len()..].starts_with('.') {
+                key[prefix.len() + 1..].parse::<usize>().ok().and_then(|index| {
+                    self.get::<String>(key).map(|value| (index, value))
+                })
+            } else {
+                None
+            }
+        }).collect::<Vec<_>>();
+        values.sort_by_key(|&(index, _)| index);
+        values
+    }
+
     fn expanded(&self) -> Ref<BTreeMap<String, String>> {
         {
             let mut expanded = self.expanded.borrow_mut();
             if expanded.is_none() {
                 let mut prefs = self.unexpanded.clone();
-                lazy_static! {
-                    static ref REGEX: Regex = Regex::new(r#"\{(\S+?)\}"#).unwrap();
-                }
                 for _ in 0 .. 10 {
                     let mut new_prefs = BTreeMap::new();
                     for (key, value) in &prefs {
-                        new_prefs.insert(key.clone(), REGEX.replace_all(value, |captures: &Captures| {
-                            prefs.get(&captures[1])
-                                .cloned()
-                                .unwrap_or_else(|| captures[0].to_string())
-                        }).replace("{{", "{").replace("}}", "}"));
+                        new_prefs.insert(key.clone(), substitute(value, &prefs));
                     }
                     if prefs == new_prefs {
                         break;
@@ -80,6 +118,10 @@ impl Preferences {
         Ref::map(expanded, |expanded| expanded.as_ref().unwrap())
     }
 
+    pub fn expand_one(&self, template: &str) -> String {
+        substitute(template, &self.expanded())
+    }
+
     pub fn keys(&self) -> btree_map::Keys<String, String> {
         self.unexpanded.keys()
     }
@@ -89,6 +131,17 @@ impl Preferences {
     // }
 }
 
+fn substitute(value: &str, prefs: &BTreeMap<String, String>) -> String {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r#"\{(\S+?)\}"#).unwrap();
+    }
+    REGEX.replace_all(value, |captures: &Captures| {
+        prefs.get(&captures[1])
+            .cloned()
+            .unwrap_or_else(|| captures[0].to_string())
+    }).replace("{{", "{").replace("}}", "}")
+}
+
 impl Display for Preferences {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         for (key, value) in self.expanded().iter() {
@@ -97,3 +150,43 @@ impl Display for Preferences {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Preferences;
+
+    #[test]
+    fn indexed_values_are_ordered_numerically() {
+        let prefs = Preferences::parse("recipe.hooks.prebuild.10.pattern=ten\n\
+                                        recipe.hooks.prebuild.2=two\n\
+                                        recipe.hooks.prebuild.10=ten {build.arch}\n\
+                                        recipe.hooks.prebuild.1=one\n\
+                                        recipe.hooks.prebuild.pattern=none\n\
+                                        recipe.hooks.prebuilds.3=other\n\
+                                        build.arch=avr\n");
+        assert_eq!(prefs.get_indexed("recipe.hooks.prebuild"), vec![
+            (1, "one".to_string()),
+            (2, "two".to_string()),
+            (10, "ten avr".to_string())
+        ]);
+        assert!(prefs.get_indexed("recipe.hooks.postbuild").is_empty());
+    }
+
+    #[test]
+    fn expand_one_resolves_tokens_against_the_preferences() {
+        let prefs = Preferences::parse("compiler.path=/tools/{build.arch}/\nbuild.arch=avr\n");
+        assert_eq!(prefs.expand_one("{compiler.path}gcc"), "/tools/avr/gcc");
+        assert_eq!(prefs.expand_one("{build.mcu} {build.arch}"), "{build.mcu} avr");
+        assert_eq!(prefs.expand_one("-DPATTERN={{build.arch}}"), "-DPATTERN={build.arch}");
+    }
+
+    #[test]
+    fn try_parse_rejects_output_without_preferences() {
+        assert!(Preferences::try_parse("").is_err());
+        assert!(Preferences::try_parse("\n\n").is_err());
+        assert!(Preferences::try_parse("Board arduino:avr:unoo not found\nexit status 1\n").is_err());
+
+        let prefs = Preferences::try_parse("name=Arduino/Genuino Uno\nupload.speed=115200\n").unwrap();
+        assert_eq!(prefs.get::<u32>("upload.speed"), Some(115200));
+    }
+}
@@ -0,0 +1,430 @@
+#![cfg(unix)]
+
+extern crate carguino_build;
+extern crate tempdir;
+
+use carguino_build::{Config, ErrorKind, Language, Preferences};
+use carguino_build::config::Options;
+
+use tempdir::TempDir;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// The compile recipes only leave a marker next to the object file, named after the compiler that ran, and record
+// their arguments.
+const PREFS: &'static str = "build.board=AVR_UNO\nbuild.core=arduino\nbuild.core.path=/nonexistent/core\n\
+                             build.mcu=atmega328p\nbuild.usb_product=Arduino {build.board}\n\
+                             recipe.c.o.pattern=sh -c 'touch \"$0.c\"; echo \"$@\" > \"$0.args\"' \
+                             {object_file} {includes}\n\
+                             recipe.cpp.o.pattern=sh -c 'touch \"$0.cpp\"; echo \"$@\" > \"$0.args\"' \
+                             {object_file} {includes}\n\
+                             recipe.ar.pattern=true\n";
+
+fn options() -> Options {
+    Options {
+        board_id: "arduino:avr:uno".to_string(),
+        include_dirs: vec![PathBuf::from("/nonexistent/shared/include")],
+        quiet: true,
+        link: true,
+        .. Options::default()
+    }
+}
+
+// Each test builds in its own directory, which is also the build script's output directory.
+fn config(out_dir: &Path) -> Config {
+    config_with(PREFS, options(), out_dir)
+}
+
+fn config_with(prefs: &str, options: Options, out_dir: &Path) -> Config {
+    let flags = Config::serialize_flags(&options).unwrap();
+    let config = Config::serialize(Preferences::parse(prefs), "avr-atmel-none", "avr", HashMap::new(), options).unwrap();
+    Config::from_serialized(&config, Some(flags.as_str()), out_dir).unwrap()
+}
+
+// Builds the given sources into a library named `test`, in the directory of the sources.
+fn build(config: &Config, dir: &Path, sources: &[&str]) {
+    sources.iter().fold(config.builder().target_dir(dir), |builder, source| builder.source(dir.join(source)))
+           .build("test")
+           .unwrap();
+}
+
+// The arguments a compile recipe recorded for the object of the given source.
+fn compile_args(dir: &Path, source: &str) -> String {
+    let mut args = String::new();
+    let args_file = dir.join("obj/test").join(source).with_extension("o.args");
+    File::open(args_file).unwrap().read_to_string(&mut args).unwrap();
+    args
+}
+
+#[test]
+fn source_as_overrides_the_language_of_the_extension() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("plain.c")).unwrap();
+    File::create(dir.path().join("forced.c")).unwrap();
+
+    let config = config(dir.path());
+    config.builder()
+          .target_dir(dir.path())
+          .source(dir.path().join("plain.c"))
+          .source_as(dir.path().join("forced.c"), Language::Cpp)
+          .build("test")
+          .unwrap();
+
+    let object_dir = dir.path().join("obj/test");
+    assert!(object_dir.join("plain.o.c").is_file());
+    assert!(object_dir.join("forced.o.cpp").is_file());
+    assert!(!object_dir.join("forced.o.c").is_file());
+}
+
+#[test]
+fn pref_returns_the_expanded_preference() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    let config = config(dir.path());
+    assert_eq!(config.pref("build.usb_product"), Some("Arduino AVR_UNO".to_string()));
+    assert_eq!(config.pref("build.usb_manufacturer"), None);
+}
+
+#[test]
+fn define_sets_are_built_into_separate_archives() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("core.c")).unwrap();
+
+    let config = config(dir.path());
+    let build = |define_set: &str, define: &str| {
+        config.builder()
+              .target_dir(dir.path())
+              .source(dir.path().join("core.c"))
+              .define(define, None)
+              .define_set(define_set)
+              .build_with_outputs("core")
+              .unwrap()
+    };
+    let test = build("test", "TEST_HARNESS");
+    let release = build("release", "NDEBUG");
+
+    assert!(test.archive_file != release.archive_file);
+    assert!(test.object_files[0] != release.object_files[0]);
+    assert!(test.object_files[0].with_extension("o.c").is_file());
+    assert!(release.object_files[0].with_extension("o.c").is_file());
+}
+
+#[test]
+fn include_dirs_are_passed_to_every_compilation() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    let config = config(dir.path());
+    config.builder()
+          .target_dir(dir.path())
+          .source(dir.path().join("module.c"))
+          .build("test")
+          .unwrap();
+
+    let mut args = String::new();
+    File::open(dir.path().join("obj/test/module.o.args")).unwrap().read_to_string(&mut args).unwrap();
+    assert!(args.contains("-I/nonexistent/core -I/nonexistent/shared/include"), "{}", args);
+}
+
+// Unlike the shared recipes, this one creates the object, so it is only compiled again when it has to be.
+fn core_prefs(dir: &Path) -> String {
+    let core_dir = dir.join("core");
+    fs::create_dir_all(&core_dir).unwrap();
+    File::create(core_dir.join("wiring.c")).unwrap();
+    format!("build.board=AVR_UNO\nbuild.core=arduino\nbuild.core.path={}\nbuild.mcu=atmega328p\n\
+             recipe.c.o.pattern=sh -c 'touch \"$0\" \"$0.c\"' {{object_file}}\n\
+             recipe.cpp.o.pattern=true\nrecipe.ar.pattern=true\n", core_dir.display())
+}
+
+// Builds the core of `core_prefs` and tells whether its source was compiled.
+fn build_core(config: &Config, dir: &Path) -> bool {
+    config.builder().target_dir(dir).core_sources().build("core").unwrap();
+    let marker = dir.join("obj/core/wiring.o.c");
+    let compiled = marker.is_file();
+    if compiled {
+        fs::remove_file(&marker).unwrap();
+    }
+    compiled
+}
+
+#[test]
+fn rebuild_requests_recompile_the_core_once() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    let request_file = dir.path().join("rebuild-core");
+    let config = config_with(&core_prefs(dir.path()), Options {
+        rebuild_core_file: Some(request_file.clone()),
+        .. options()
+    }, dir.path());
+
+    assert!(build_core(&config, dir.path()));
+    assert!(!build_core(&config, dir.path()));
+    File::create(&request_file).unwrap().write_all(b"1").unwrap();
+    assert!(build_core(&config, dir.path()));
+    assert!(!build_core(&config, dir.path()));
+}
+
+#[test]
+fn changed_defines_recompile_the_core() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    let prefs = core_prefs(dir.path());
+    let with_defines = |defines: &[&str]| {
+        config_with(&prefs, Options {
+            defines: defines.iter().map(|define| define.to_string()).collect(),
+            .. options()
+        }, dir.path())
+    };
+
+    assert!(build_core(&with_defines(&["-DENABLE_LOGGING"]), dir.path()));
+    assert!(!build_core(&with_defines(&["-DENABLE_LOGGING"]), dir.path()));
+    assert!(build_core(&with_defines(&[]), dir.path()));
+    assert!(!build_core(&with_defines(&[]), dir.path()));
+}
+
+#[test]
+fn assembly_is_skipped_without_an_assembler_recipe() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+    File::create(dir.path().join("startup.S")).unwrap();
+
+    let config = config(dir.path());
+    let outputs = config.builder()
+                        .target_dir(dir.path())
+                        .source(dir.path().join("module.c"))
+                        .source(dir.path().join("startup.S"))
+                        .build_with_outputs("test")
+                        .unwrap();
+
+    assert_eq!(outputs.object_files, vec![dir.path().join("obj/test/module.o")]);
+    assert!(dir.path().join("obj/test/module.o.c").is_file());
+}
+
+#[test]
+fn compile_recipes_are_required() {
+    let prefs = PREFS.lines().filter(|line| !line.starts_with("recipe.c.o.pattern=")).collect::<Vec<_>>().join("\n");
+    let error = Config::serialize(Preferences::parse(&prefs), "avr-atmel-none", "avr", HashMap::new(), options())
+        .unwrap_err();
+    match *error.kind() {
+        ErrorKind::MissingRecipe(ref key) => assert_eq!(key, "recipe.c.o.pattern"),
+        ref kind => panic!("unexpected error: {}", kind)
+    }
+}
+
+#[test]
+fn archive_recipes_get_the_archive_path_and_file_name() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    // Older platforms combine `archive_file` with `build.path`, which is replaced by the archive's full path.
+    let prefs = PREFS.replace("recipe.ar.pattern=true",
+                              "recipe.ar.pattern=sh -c 'echo \"$1\" > \"$0.name\"' \"{build.path}/{archive_file}\" \
+                               \"{archive_file}\"");
+    let config = config_with(&prefs, options(), dir.path());
+    let outputs = config.builder()
+                        .target_dir(dir.path())
+                        .source(dir.path().join("module.c"))
+                        .build_with_outputs("test")
+                        .unwrap();
+
+    let mut name = String::new();
+    File::open(outputs.archive_file.with_extension("a.name")).unwrap().read_to_string(&mut name).unwrap();
+    assert_eq!(name, "libtest.a\n");
+}
+
+#[test]
+fn sources_are_compiled_with_a_section_per_function() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    build(&config(dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert!(args.starts_with("-ffunction-sections -fdata-sections "), "{}", args);
+
+    // Recipes that already have the flags do not get them twice.
+    let prefs = PREFS.replace("{object_file} {includes}", "{object_file} -ffunction-sections {includes}");
+    build(&config_with(&prefs, options(), dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert_eq!(args.matches("-ffunction-sections").count(), 1, "{}", args);
+    assert!(args.contains("-fdata-sections"), "{}", args);
+}
+
+#[test]
+fn defines_are_passed_to_every_compilation_but_not_the_archiver() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+    File::create(dir.path().join("sketch.cpp")).unwrap();
+
+    let prefs = PREFS.replace("recipe.ar.pattern=true",
+                              "recipe.ar.pattern=sh -c 'echo \"$@\" >> \"$0.args\"' {archive_file_path} {object_file}");
+    config_with(&prefs, options(), dir.path()).builder()
+        .target_dir(dir.path())
+        .source(dir.path().join("module.c"))
+        .source(dir.path().join("sketch.cpp"))
+        .define("LED_PIN", "13")
+        .define("SERIAL_DEBUG", None)
+        .undef("NDEBUG")
+        .build("test")
+        .unwrap();
+
+    for source in &["module.c", "sketch.cpp"] {
+        let args = compile_args(dir.path(), source);
+        assert!(args.contains("-DLED_PIN=13 -DSERIAL_DEBUG -UNDEBUG"), "{}", args);
+    }
+    let mut archiver_args = String::new();
+    File::open(dir.path().join("libtest.a.args")).unwrap().read_to_string(&mut archiver_args).unwrap();
+    assert!(archiver_args.contains("module.o") && !archiver_args.contains("-D"), "{}", archiver_args);
+}
+
+#[test]
+fn variant_includes_can_come_before_the_core() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    let prefs = format!("{}build.variant=standard\nbuild.variant.path=/nonexistent/variants/standard\n", PREFS);
+    build(&config_with(&prefs, options(), dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert!(args.contains("-I/nonexistent/core -I/nonexistent/variants/standard -I/nonexistent/shared/include"),
+            "{}", args);
+
+    let variant_first = Options {
+        variant_includes_first: true,
+        .. options()
+    };
+    build(&config_with(&prefs, variant_first, dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert!(args.contains("-I/nonexistent/variants/standard -I/nonexistent/core -I/nonexistent/shared/include"),
+            "{}", args);
+}
+
+#[test]
+fn board_defines_are_derived_from_the_preferences() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    build(&config(dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert!(args.contains("-DARDUINO=10800 -DARDUINO_AVR_UNO -DARDUINO_ARCH_AVR -I"), "{}", args);
+
+    // Recipes that define a macro themselves keep their value.
+    let prefs = PREFS.replace("{object_file} {includes}", "{object_file} -DARDUINO_ARCH_AVR=1 {includes}")
+                     .replace("build.board=AVR_UNO\n", "build.board=AVR_UNO\nruntime.ide.version=10819\n");
+    build(&config_with(&prefs, options(), dir.path()), dir.path(), &["module.c"]);
+    let args = compile_args(dir.path(), "module.c");
+    assert!(args.contains("-DARDUINO=10819 -DARDUINO_AVR_UNO -I"), "{}", args);
+    assert_eq!(args.matches("-DARDUINO_ARCH_AVR").count(), 1, "{}", args);
+}
+
+#[test]
+fn another_ide_version_recompiles_the_core() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    let prefs = core_prefs(dir.path());
+    let config = config_with(&prefs, options(), dir.path());
+    assert!(build_core(&config, dir.path()));
+    assert!(!build_core(&config, dir.path()));
+
+    let config = config_with(&format!("{}runtime.ide.version=10819\n", prefs), options(), dir.path());
+    assert!(build_core(&config, dir.path()));
+    assert!(!build_core(&config, dir.path()));
+}
+
+#[test]
+fn objects_are_kept_apart_from_the_archive() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    let config = config(dir.path());
+    let outputs = config.builder()
+                        .target_dir(dir.path().join("lib"))
+                        .source(dir.path().join("module.c"))
+                        .build_with_outputs("test")
+                        .unwrap();
+    assert_eq!(outputs.archive_file, dir.path().join("lib/libtest.a"));
+    assert_eq!(outputs.object_files, vec![dir.path().join("lib/obj/test/module.o")]);
+
+    let outputs = config.builder()
+                        .target_dir(dir.path().join("lib"))
+                        .object_dir(dir.path().join("objects"))
+                        .source(dir.path().join("module.c"))
+                        .build_with_outputs("test")
+                        .unwrap();
+    assert_eq!(outputs.archive_file, dir.path().join("lib/libtest.a"));
+    assert_eq!(outputs.object_files, vec![dir.path().join("objects/module.o")]);
+    assert!(dir.path().join("objects/module.o.c").is_file());
+}
+
+#[test]
+fn object_files_are_passed_to_the_archiver_as_separate_arguments() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("main.c")).unwrap();
+    File::create(dir.path().join("serial port.c")).unwrap();
+
+    let prefs = PREFS.replace("recipe.ar.pattern=true",
+                              "recipe.ar.pattern=sh -c 'for object; do echo \"$object\"; done >> \"$0.list\"' \
+                               {archive_file_path} {object_files}");
+    build(&config_with(&prefs, options(), dir.path()), dir.path(), &["main.c", "serial port.c"]);
+
+    let mut objects = String::new();
+    File::open(dir.path().join("libtest.a.list")).unwrap().read_to_string(&mut objects).unwrap();
+    let object_dir = dir.path().join("obj/test");
+    assert_eq!(objects, format!("{}\n{}\n", object_dir.join("main.o").display(), object_dir.join("serial port.o").display()));
+}
+
+#[test]
+fn paths_with_spaces_are_passed_as_single_arguments() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    let sketch_dir = dir.path().join("My Sketch");
+    fs::create_dir(&sketch_dir).unwrap();
+    File::create(sketch_dir.join("blink module.c")).unwrap();
+
+    // The recipe only leaves its marker next to the object if the source and object paths each arrive as a single
+    // argument.
+    let prefs = PREFS.replace("recipe.c.o.pattern=sh -c '", "recipe.c.o.pattern=sh -c 'test -f \"$1\" || exit 1; ")
+                     .replace("{object_file} {includes}", "{object_file} {source_file} {includes}");
+    let target_dir = dir.path().join("Build Output");
+    config_with(&prefs, options(), dir.path()).builder()
+        .target_dir(&target_dir)
+        .source(sketch_dir.join("blink module.c"))
+        .build("test")
+        .unwrap();
+    assert!(target_dir.join("obj/test/blink module.o.c").is_file());
+}
+
+#[test]
+fn recipe_overrides_replace_the_platform_recipes() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    let mut recipe_overrides = HashMap::new();
+    recipe_overrides.insert("recipe.c.o.pattern".to_string(),
+                            "sh -c 'touch \"$0.override\"' {object_file} {source_file}".to_string());
+    let overridden = Options {
+        recipe_overrides: recipe_overrides,
+        .. options()
+    };
+    build(&config_with(PREFS, overridden, dir.path()), dir.path(), &["module.c"]);
+    assert!(dir.path().join("obj/test/module.o.override").is_file());
+    assert!(!dir.path().join("obj/test/module.o.c").is_file());
+
+    let mut recipe_overrides = HashMap::new();
+    recipe_overrides.insert("recipe.c.o.pattern".to_string(), "avr-gcc -c {source_file}".to_string());
+    let invalid = Options {
+        recipe_overrides: recipe_overrides,
+        .. options()
+    };
+    let error = Config::serialize(Preferences::parse(PREFS), "avr-atmel-none", "avr", HashMap::new(), invalid).unwrap_err();
+    assert!(error.to_string().contains("missing the placeholder {object_file}"), "{}", error);
+}
+
+#[test]
+fn boards_without_a_variant_directory_only_include_the_core() {
+    let dir = TempDir::new("carguino-build-test").unwrap();
+    File::create(dir.path().join("module.c")).unwrap();
+
+    for variant in &["build.variant=\n", "build.variant=standard\n"] {
+        let prefs = format!("{}{}", PREFS, variant);
+        build(&config_with(&prefs, options(), dir.path()), dir.path(), &["module.c"]);
+        let args = compile_args(dir.path(), "module.c");
+        assert!(args.ends_with(" -I/nonexistent/core -I/nonexistent/shared/include\n"), "{}", args);
+    }
+}
@@ -1,19 +1,25 @@
-use Result;
+use {Result, ResultExt};
+use serial;
+
+use carguino_build::Preferences;
 
 use regex::Regex;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::env;
 use std::fmt::{self, Display, Formatter};
+use std::fs;
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
 #[serde(deny_unknown_fields)]
 pub struct BoardInfo {
     vendor: String,
     arch: String,
     board: String,
     #[serde(default)]
-    params: HashMap<String, String>
+    params: BTreeMap<String, String>
 }
 
 impl BoardInfo {
@@ -24,7 +30,7 @@ impl BoardInfo {
             ).unwrap();
         }
         REGEX.captures(fqbn).map(|captures| {
-            let params = HashMap::from_iter(captures.get(4).iter().flat_map(|capture| {
+            let params = BTreeMap::from_iter(captures.get(4).iter().flat_map(|capture| {
             capture.as_str().split(',')
             }).map(|pair| {
                 let mut iter = pair.split('=');
@@ -50,6 +56,14 @@ impl BoardInfo {
     pub fn board(&self) -> &str {
         &self.board
     }
+
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+
+    pub fn set_param(&mut self, key: &str, value: &str) {
+        self.params.insert(key.to_string(), value.to_string());
+    }
 }
 
 impl Display for BoardInfo {
@@ -61,3 +75,116 @@ impl Display for BoardInfo {
         Ok(())
     }
 }
+
+// Platforms are either in `<hardware>/<vendor>/<arch>` or installed by the board manager in
+// `<packages>/<vendor>/hardware/<arch>/<version>`; the USB IDs of each board are read from its `boards.txt`.
+pub fn installed_boards(hardware_dirs: &[PathBuf]) -> Result<Vec<(BoardInfo, Vec<(u16, u16)>)>> {
+    let mut platforms = Vec::new();
+    for hardware_dir in hardware_dirs {
+        for (vendor, vendor_dir) in subdirs(hardware_dir) {
+            for (arch, arch_dir) in subdirs(&vendor_dir) {
+                platforms.push((vendor.clone(), arch, arch_dir));
+            }
+        }
+    }
+    if let Some(packages_dir) = packages_dir() {
+        for (vendor, vendor_dir) in subdirs(&packages_dir) {
+            for (arch, arch_dir) in subdirs(&vendor_dir.join("hardware")) {
+                for (_, version_dir) in subdirs(&arch_dir) {
+                    platforms.push((vendor.clone(), arch.clone(), version_dir));
+                }
+            }
+        }
+    }
+
+    let mut boards = Vec::new();
+    for (vendor, arch, platform_dir) in platforms {
+        let boards_file = platform_dir.join("boards.txt");
+        if !boards_file.is_file() {
+            continue;
+        }
+        let prefs = Preferences::parse_file(&boards_file).chain_err(|| {
+            format!("Could not read board definitions '{}'", boards_file.display())
+        })?;
+        let names = prefs.keys().filter(|key| key.ends_with(".name") && key.matches('.').count() == 1)
+                                .map(|key| key[..key.len() - ".name".len()].to_string()).collect::<Vec<_>>();
+        for name in names {
+            let prefix = format!("{}.", name);
+            let mut board_prefs = Preferences::new();
+            for key in prefs.keys().filter(|key| key.starts_with(&prefix)) {
+                board_prefs.set(&key[prefix.len()..], prefs.get_unexpanded::<String>(key).unwrap());
+            }
+            let board = BoardInfo {
+                vendor: vendor.clone(),
+                arch: arch.clone(),
+                board: name,
+                params: BTreeMap::new()
+            };
+            boards.push((board, serial::usb_ids(&board_prefs)));
+        }
+    }
+    Ok(boards)
+}
+
+fn subdirs(dir: &Path) -> Vec<(String, PathBuf)> {
+    fs::read_dir(dir).map(|entries| {
+        entries.filter_map(|entry| entry.ok()).filter(|entry| entry.path().is_dir()).map(|entry| {
+            (entry.file_name().to_string_lossy().to_string(), entry.path())
+        }).collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(target_os = "macos")]
+fn packages_dir() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join("Library/Arduino15/packages"))
+}
+
+#[cfg(windows)]
+fn packages_dir() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("Arduino15").join("packages"))
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn packages_dir() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".arduino15/packages"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoardInfo;
+
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash(board: &BoardInfo) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        board.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn boards_with_the_same_fqbn_are_equal() {
+        let board = BoardInfo::from_fqbn("arduino:avr:nano:cpu=atmega328old").unwrap();
+        let same_board = BoardInfo::from_fqbn("arduino:avr:nano:cpu=atmega328old").unwrap();
+        assert_eq!(board, same_board);
+        assert_eq!(hash(&board), hash(&same_board));
+
+        let other_board = BoardInfo::from_fqbn("arduino:avr:nano:cpu=atmega328").unwrap();
+        assert!(board != other_board);
+    }
+
+    #[test]
+    fn params_are_displayed_in_a_fixed_order() {
+        let mut board = BoardInfo::from_fqbn("arduino:avr:mega").unwrap();
+        board.set_param("cpu", "atmega2560");
+        board.set_param("baud", "57600");
+        let mut other_board = BoardInfo::from_fqbn("arduino:avr:mega").unwrap();
+        other_board.set_param("baud", "57600");
+        other_board.set_param("cpu", "atmega2560");
+
+        assert_eq!(board.to_string(), "arduino:avr:mega:baud=57600,cpu=atmega2560");
+        assert_eq!(board.to_string(), other_board.to_string());
+        assert_eq!(BoardInfo::from_fqbn("arduino:avr:mega:cpu=atmega2560,baud=57600").unwrap().to_string(),
+                   board.to_string());
+    }
+}
@@ -1,15 +1,108 @@
-use {BoardInfo, Result};
+use {BoardInfo, Result, ResultExt};
 
 use cargo::util::{self, ProcessBuilder};
 use carguino_build::Preferences;
 
+use regex::Regex;
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Backend {
+    ArduinoBuilder,
+    ArduinoCli
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Option<Backend>> {
+        match name {
+            "auto" => Some(None),
+            "arduino-builder" => Some(Some(Backend::ArduinoBuilder)),
+            "arduino-cli" => Some(Some(Backend::ArduinoCli)),
+            _ => None
+        }
+    }
 
-#[derive(Debug)]
+    // arduino-builder is preferred, as it is the one that can be pointed at a specific IDE installation.
+    pub fn detect(home: Option<&Path>) -> Backend {
+        let has_arduino_builder = home.map_or(false, |home| program(Some(home)).is_file()) ||
+                                  can_run("arduino-builder", "-version");
+        if !has_arduino_builder && can_run("arduino-cli", "version") {
+            Backend::ArduinoCli
+        } else {
+            Backend::ArduinoBuilder
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::ArduinoBuilder => "arduino-builder",
+            Backend::ArduinoCli => "arduino-cli"
+        }
+    }
+
+    fn prefs_source(&self) -> &'static PrefsSource {
+        static ARDUINO_BUILDER: ArduinoBuilder = ArduinoBuilder;
+        static ARDUINO_CLI: ArduinoCli = ArduinoCli;
+        match *self {
+            Backend::ArduinoBuilder => &ARDUINO_BUILDER,
+            Backend::ArduinoCli => &ARDUINO_CLI
+        }
+    }
+}
+
+trait PrefsSource {
+    fn command(&self, builder: &Builder, src: &Path) -> Result<ProcessBuilder>;
+}
+
+struct ArduinoBuilder;
+
+impl PrefsSource for ArduinoBuilder {
+    fn command(&self, builder: &Builder, src: &Path) -> Result<ProcessBuilder> {
+        let mut command = builder.base_command();
+        command.arg("-dump-prefs").arg(short_path(src));
+        Ok(command)
+    }
+}
+
+// arduino-cli finds the installed platforms and tools by itself, so only the libraries and preferences are passed on.
+struct ArduinoCli;
+
+impl PrefsSource for ArduinoCli {
+    fn command(&self, builder: &Builder, src: &Path) -> Result<ProcessBuilder> {
+        // arduino-cli only accepts sketches, which are directories containing an `.ino` file of the same name.
+        let sketch_dir = src.with_file_name("sketch");
+        fs::create_dir_all(&sketch_dir).and_then(|_| File::create(sketch_dir.join("sketch.ino")))
+                                       .chain_err(|| "Could not create temporary sketch")?;
+
+        let mut command = util::process("arduino-cli");
+        command.arg("compile").arg("--show-properties")
+               .arg("--fqbn").arg(&builder.board)
+               .arg("--warnings").arg("all")
+               .arg("--build-property").arg("compiler.warning_flags={compiler.warning_flags.all}");
+        for path in &builder.libraries {
+            command.arg("--libraries").arg(path);
+        }
+        for pref in &builder.prefs {
+            command.arg("--build-property").arg(pref);
+        }
+        command.arg(sketch_dir);
+        Ok(command)
+    }
+}
+
+#[derive(Debug, Hash)]
 pub struct Builder {
+    backend: Backend,
     prefs: Vec<String>,
     board: String,
     home: Option<PathBuf>,
+    tools_builder: Option<PathBuf>,
+    avr_tools: Option<PathBuf>,
     hardware: Vec<PathBuf>,
     tools: Vec<PathBuf>,
     libraries: Vec<PathBuf>,
@@ -17,11 +110,15 @@ pub struct Builder {
 }
 
 impl Builder {
+    // The board's menu options are part of its FQBN, so the preferences (and their cache) reflect the selected ones.
     pub fn new(board: &BoardInfo) -> Builder {
         Builder {
+            backend: Backend::ArduinoBuilder,
             prefs: Vec::new(),
             board: board.to_string(),
             home: None,
+            tools_builder: None,
+            avr_tools: None,
             hardware: Vec::new(),
             tools: Vec::new(),
             libraries: Vec::new(),
@@ -29,11 +126,30 @@ impl Builder {
         }
     }
 
+    pub fn backend(&mut self, backend: Backend) -> &mut Builder {
+        self.backend = backend;
+        self
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
     pub fn home<P: Into<PathBuf>>(&mut self, path: P) -> &mut Builder {
         self.home = Some(path.into());
         self
     }
 
+    pub fn tools_builder<P: Into<PathBuf>>(&mut self, path: P) -> &mut Builder {
+        self.tools_builder = Some(path.into());
+        self
+    }
+
+    pub fn avr_tools<P: Into<PathBuf>>(&mut self, path: P) -> &mut Builder {
+        self.avr_tools = Some(path.into());
+        self
+    }
+
     pub fn hardware<P: Into<PathBuf>>(&mut self, path: P) -> &mut Builder {
         self.hardware.push(path.into());
         self
@@ -54,18 +170,38 @@ impl Builder {
         self
     }
 
+    pub fn prefs_file<P: Into<PathBuf>>(&mut self, path: P) -> Result<&mut Builder> {
+        let prefs = Preferences::parse_file(path.into())?;
+        for key in prefs.keys() {
+            self.pref(key, prefs.get_unexpanded::<String>(key).unwrap());
+        }
+        Ok(self)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
     fn base_command(&self) -> ProcessBuilder {
         let mut command = if let Some(ref home) = self.home { //self.home.or_else(|| env::var_os("ARDUINO_HOME").map(PathBuf::from)) {
-            let mut command = util::process(home.join("arduino-builder"));
+            let mut command = util::process(program(Some(home)));
             command.arg("-built-in-libraries").arg(home.join("libraries"))
-                   .arg("-hardware").arg(home.join("hardware"))
-                   .arg("-tools").arg(home.join("hardware/tools/avr"))
-                   .arg("-tools").arg(home.join("tools-builder"));
+                   .arg("-hardware").arg(home.join("hardware"));
             command
         } else {
-            util::process("arduino-builder")
+            util::process(program(None))
         };
 
+        // Distribution packages and board manager installs keep these tools outside of the IDE's directory.
+        let home = self.home.as_ref();
+        let avr_tools = self.avr_tools.clone().or_else(|| home.map(|home| home.join("hardware/tools/avr")));
+        let tools_builder = self.tools_builder.clone().or_else(|| home.map(|home| home.join("tools-builder")));
+        for path in avr_tools.iter().chain(&tools_builder) {
+            command.arg("-tools").arg(path);
+        }
+
         for path in &self.hardware {
             command.arg("-hardware").arg(path);
         }
@@ -91,13 +227,115 @@ impl Builder {
     }
 
     pub fn dump_prefs(&self, src: &Path) -> Result<Preferences> {
-        let output = self.base_command()
-                         .arg("-dump-prefs")
-                         .arg(src)
-                         .exec_with_output()?;
+        let output = self.backend.prefs_source().command(self, src)?.exec_with_output()?;
 
-        let prefs = Preferences::parse(String::from_utf8_lossy(&output.stdout));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let prefs = parse_dumped_prefs(&stdout).chain_err(|| {
+            format!("{} did not report any preferences:\n{}", self.backend.name(), stdout.trim())
+        })?;
 
         Ok(prefs)
     }
 }
+
+// Some versions print banner or progress lines in between the preferences.
+fn parse_dumped_prefs(stdout: &str) -> Result<Preferences> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r#"^[\w.-]+="#).unwrap();
+    }
+
+    let lines = stdout.lines().filter(|line| REGEX.is_match(line)).collect::<Vec<_>>();
+    Ok(Preferences::try_parse(lines.join("\n"))?)
+}
+
+fn can_run(program: &str, flag: &str) -> bool {
+    Command::new(program).arg(flag).stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok()
+}
+
+pub fn program(home: Option<&Path>) -> PathBuf {
+    home.map_or_else(|| PathBuf::from("arduino-builder"), |home| home.join("arduino-builder"))
+}
+
+// arduino-builder fails on paths approaching MAX_PATH, so long paths are passed in their 8.3 form.
+#[cfg(windows)]
+fn short_path(path: &Path) -> PathBuf {
+    use kernel32;
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::ptr;
+
+    if path.as_os_str().len() < SHORT_PATH_THRESHOLD {
+        return path.to_path_buf();
+    }
+
+    let long_path = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let len = unsafe { kernel32::GetShortPathNameW(long_path.as_ptr(), ptr::null_mut(), 0) };
+    if len == 0 {
+        return path.to_path_buf();
+    }
+
+    let mut short_path = vec![0u16; len as usize];
+    let len = unsafe { kernel32::GetShortPathNameW(long_path.as_ptr(), short_path.as_mut_ptr(), len) };
+    if len == 0 || len as usize >= short_path.len() {
+        return path.to_path_buf();
+    }
+    short_path.truncate(len as usize);
+    PathBuf::from(OsString::from_wide(&short_path))
+}
+
+#[cfg(windows)]
+const SHORT_PATH_THRESHOLD: usize = 200;
+
+#[cfg(not(windows))]
+fn short_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_dumped_prefs;
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_are_shortened() {
+        use super::{short_path, SHORT_PATH_THRESHOLD};
+        use tempdir::TempDir;
+
+        use std::fs::{self, File};
+
+        let temp_dir = TempDir::new("carguino").unwrap();
+        let short_file = temp_dir.path().join("project.c");
+        assert_eq!(short_path(&short_file), short_file);
+
+        let mut long_dir = temp_dir.path().to_path_buf();
+        while long_dir.as_os_str().len() < SHORT_PATH_THRESHOLD {
+            long_dir.push("a directory name long enough to have a different short name");
+        }
+        fs::create_dir_all(&long_dir).unwrap();
+        let long_file = long_dir.join("project.c");
+        File::create(&long_file).unwrap();
+
+        // Volumes without 8.3 names keep the long path.
+        let shortened = short_path(&long_file);
+        assert!(shortened.is_file(), "{}", shortened.display());
+        assert!(shortened.as_os_str().len() <= long_file.as_os_str().len());
+        assert_eq!(fs::canonicalize(&shortened).unwrap(), fs::canonicalize(&long_file).unwrap());
+
+        // Paths that do not exist cannot be shortened.
+        let missing_file = long_dir.join("missing.c");
+        assert_eq!(short_path(&missing_file), missing_file);
+    }
+
+    #[test]
+    fn only_preference_lines_of_a_dump_are_parsed() {
+        let prefs = parse_dumped_prefs("Loading libraries...\n\
+                                        build.mcu=atmega328p\n\
+                                        Progress 50.00%\n\
+                                        ===info ||| Using board 'uno'\n\
+                                        build.f_cpu=16000000L\n\
+                                        tools.avrdude.cmd.path={path}/bin/avrdude\n").unwrap();
+        assert_eq!(prefs.keys().cloned().collect::<Vec<_>>(), vec!["build.f_cpu", "build.mcu", "tools.avrdude.cmd.path"]);
+
+        assert!(parse_dumped_prefs("Loading libraries...\nError: board not found\n").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use carguino_build::Preferences;
+use carguino_build::config as build_config;
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// The cached data, by the option of `cache clear` that removes it.
+pub const CACHES: &'static [(&'static str, &'static [&'static str])] = &[
+    ("prefs", &["prefs"]),
+    ("targets", &["targets", "base-targets"]),
+    ("includes", &["includes"])
+];
+
+pub fn cache_dir() -> PathBuf {
+    env::var_os("CARGUINO_CACHE_DIR").map(PathBuf::from).unwrap_or_else(|| {
+        env::home_dir().unwrap().join(".carguino")
+    })
+}
+
+pub fn load_prefs(file: &Path) -> Option<Preferences> {
+    modified(file).and_then(|cache_modified| {
+        Preferences::parse_file(file).ok().and_then(|prefs| {
+            prefs.get::<String>("runtime.platform.path").map(PathBuf::from).and_then(|platform_dir| {
+                let stale = ["boards.txt", "platform.txt", "platform.local.txt"].iter().any(|name| {
+                    modified(&platform_dir.join(name)).map_or(false, |modified| modified > cache_modified)
+                });
+                if stale { None } else { Some(prefs) }
+            })
+        })
+    })
+}
+
+// A nightly's version line includes its commit hash and date, so each toolchain gets its own file.
+pub fn base_spec_file(rustc_version: &str, target: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    rustc_version.trim().hash(&mut hasher);
+    cache_dir().join("base-targets").join(format!("{}-{:016x}.json", target, hasher.finish()))
+}
+
+pub fn toolchain_exists(prefs: &Preferences) -> bool {
+    ["recipe.c.o.pattern", "recipe.cpp.o.pattern"].iter().all(|key| {
+        prefs.get::<String>(key).map_or(true, |recipe| {
+            let (command, _) = build_config::split_command_line(&recipe);
+            build_config::command_exists(&command)
+        })
+    })
+}
+
+// Symbolic links are not followed, so only the space taken by the directory itself is counted.
+pub fn dir_size(path: &Path) -> u64 {
+    fs::symlink_metadata(path).map(|metadata| {
+        if metadata.is_dir() {
+            fs::read_dir(path).map(|entries| {
+                entries.filter_map(|entry| entry.ok()).map(|entry| dir_size(&entry.path())).sum()
+            }).unwrap_or(0)
+        } else {
+            metadata.len()
+        }
+    }).unwrap_or(0)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::base_spec_file;
+
+    fn file_name(rustc_version: &str, target: &str) -> String {
+        base_spec_file(rustc_version, target).file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn each_toolchain_has_its_own_base_spec_file() {
+        let nightly = file_name("rustc 1.17.0-nightly (b1e31766d 2017-02-26)", "avr-atmel-none");
+        let other_nightly = file_name("rustc 1.17.0-nightly (be760566c 2017-02-28)", "avr-atmel-none");
+        assert!(nightly != other_nightly);
+        assert!(nightly != file_name("rustc 1.17.0-nightly (b1e31766d 2017-02-26)", "thumbv6m-none-eabi"));
+        // The trailing newline of `rustc --version` does not matter.
+        assert_eq!(nightly, file_name("rustc 1.17.0-nightly (b1e31766d 2017-02-26)\n", "avr-atmel-none"));
+    }
+
+    #[test]
+    fn base_spec_file_names_only_contain_the_target_and_a_hash() {
+        let path = base_spec_file("rustc 1.17.0-dev (../rust/src 2017/02/26)", "avr-atmel-none");
+        assert!(path.parent().unwrap().ends_with("base-targets"));
+
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("avr-atmel-none-") && name.ends_with(".json"), "{}", name);
+        let hash = &name["avr-atmel-none-".len()..name.len() - ".json".len()];
+        assert_eq!(hash.len(), 16);
+        assert!(hash.chars().all(|c| c.is_digit(16)), "{}", name);
+    }
+}
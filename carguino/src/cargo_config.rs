@@ -0,0 +1,90 @@
+use {Result, ResultExt};
+
+use cargo::util::{Cfg, CfgExpr};
+
+use toml::Value;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Cargo ignores the `rustflags` of its configuration files when RUSTFLAGS is set, as it is for every build, so they are
+// collected here instead, with cargo's precedence: the matching `[target]` sections replace `[build]`.
+pub fn rustflags(project_dir: &Path, targets: &[&str], cfgs: &[Cfg]) -> Result<Vec<String>> {
+    let mut target_flags = Vec::new();
+    let mut build_flags = Vec::new();
+    for path in config_files(project_dir) {
+        let config = read_config_file(&path)?;
+        let table = match config.as_table() {
+            Some(table) => table,
+            None => continue
+        };
+
+        if let Some(sections) = table.get("target").and_then(Value::as_table) {
+            for (key, section) in sections {
+                if target_matches(key, targets, cfgs) {
+                    target_flags.extend(section_rustflags(&path, section)?);
+                }
+            }
+        }
+        if let Some(section) = table.get("build") {
+            build_flags.extend(section_rustflags(&path, section)?);
+        }
+    }
+
+    Ok(if target_flags.is_empty() { build_flags } else { target_flags })
+}
+
+// Like cargo, the files closest to the project come first, followed by the one in cargo's home directory.
+fn config_files(project_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let mut dir = Some(project_dir);
+    while let Some(current_dir) = dir {
+        dirs.push(current_dir.join(".cargo"));
+        dir = current_dir.parent();
+    }
+    let cargo_home = env::var_os("CARGO_HOME").map(PathBuf::from)
+                                              .or_else(|| env::home_dir().map(|home| home.join(".cargo")));
+    if let Some(cargo_home) = cargo_home {
+        if !dirs.contains(&cargo_home) {
+            dirs.push(cargo_home);
+        }
+    }
+
+    dirs.into_iter().filter_map(|dir| {
+        ["config", "config.toml"].iter().map(|name| dir.join(name)).find(|path| path.is_file())
+    }).collect()
+}
+
+fn read_config_file(path: &Path) -> Result<Value> {
+    let mut config = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut config)).chain_err(|| {
+        format!("Could not read cargo configuration file '{}'", path.display())
+    })?;
+    ::toml::from_str(&config).chain_err(|| format!("Could not parse cargo configuration file '{}'", path.display()))
+}
+
+fn target_matches(key: &str, targets: &[&str], cfgs: &[Cfg]) -> bool {
+    if key.starts_with("cfg(") && key.ends_with(')') {
+        key[4..key.len() - 1].parse::<CfgExpr>().map(|expr| expr.matches(cfgs)).unwrap_or(false)
+    } else {
+        targets.contains(&key)
+    }
+}
+
+// As in cargo, the flags are either an array or a single string of space-separated flags.
+fn section_rustflags(path: &Path, section: &Value) -> Result<Vec<String>> {
+    match section.as_table().and_then(|section| section.get("rustflags")) {
+        None => Ok(Vec::new()),
+        Some(&Value::String(ref flags)) => Ok(flags.split_whitespace().map(String::from).collect()),
+        Some(&Value::Array(ref flags)) => {
+            flags.iter().map(|flag| {
+                flag.as_str().map(String::from).map_or_else(|| {
+                    Err(format!("'rustflags' in '{}' must only contain strings", path.display()).into())
+                }, Ok)
+            }).collect()
+        }
+        Some(_) => bail!("'rustflags' in '{}' must be a string or an array of strings", path.display())
+    }
+}
@@ -1,30 +1,123 @@
-use board::BoardInfo;
-use builder::Builder;
-use error::{Result, ResultExt};
+use board::{self, BoardInfo};
+use builder::{Backend, Builder};
+use error::{ErrorKind, Result, ResultExt};
+use serial;
+use uf2;
 
 use cargo;
 use cargo::core::{ColorConfig, MultiShell, Verbosity};
 use cargo::ops::MessageFormat;
 use cargo::util::ProcessBuilder;
 
+use regex::Regex;
+
+use serde_json;
+
+use term::color;
+
 use toml;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Only the commands that build the package take default arguments; e.g. `clean` does not accept `--features`.
+const DEFAULT_ARGS_COMMANDS: &'static [&'static str] = &["build", "check", "clippy", "doc", "rustc", "rustdoc"];
 
 pub struct Config {
     node: Box<ConfigNode>,
+    backend: Option<String>,
+    build_info: bool,
+    config_file: Option<PathBuf>,
+    config_overrides: Vec<String>,
+    default_rustflags: bool,
+    defsyms: Vec<String>,
+    gc_sections: bool,
+    link_args: Vec<String>,
+    linker: Option<PathBuf>,
+    disassemble: Option<String>,
+    dump_config: bool,
+    dump_objects: bool,
+    emit_spec: Option<PathBuf>,
+    explain: bool,
+    extra_verbose: bool,
+    include_dirs: Vec<PathBuf>,
+    json_messages: bool,
+    manifest_path: Option<PathBuf>,
+    menus: Vec<(String, String)>,
+    opt_level: Option<String>,
+    phase: String,
     message_format: MessageFormat,
+    post_build_command: Option<String>,
+    rebuild_core: bool,
+    serial_port: Option<String>,
     shell: MultiShell,
-    target_board: Option<BoardInfo>
+    strip: bool,
+    upload_retries: u32,
+    upload_timeout: Duration,
+    target_board: Option<BoardInfo>,
+    target_board_name: Option<String>,
+    timings: bool,
+    two_pass: bool,
+    uf2: bool,
+    uf2_family: Option<u32>
 }
 
 impl Config {
     pub fn parse_files(&mut self, current_dir: &Path) -> Result<()> {
-        self.node = ConfigNode::load(Some(current_dir))?;
+        // The configuration belongs to the project, which is not in the current directory with `--manifest-path`.
+        self.manifest_path = self.manifest_path.take().map(|manifest_path| current_dir.join(manifest_path));
+        let project_dir = self.manifest_path.as_ref().and_then(|manifest_path| manifest_path.parent())
+                                            .map_or_else(|| current_dir.to_path_buf(), Path::to_path_buf);
+        let node = ConfigNode::load(Some(&project_dir))?;
+        self.node = match self.config_file.take() {
+            Some(config_file) => {
+                let config_file = current_dir.join(config_file);
+                if !config_file.is_file() {
+                    bail!(ErrorKind::InvalidArgument(format!("Configuration file '{}' does not exist",
+                                                             config_file.display())));
+                }
+                ConfigNode::with_file(node, &config_file)?
+            }
+            None => node
+        };
+
+        if let Some(name) = self.target_board_name.take() {
+            self.target_board = Some(if name == "auto" {
+                self.detect_board()?
+            } else {
+                named_board(&self.node, &name)?
+            });
+        } else if self.target_board.is_none() {
+            self.target_board = match self.node.board_choice() {
+                Some(BoardChoice::Fqbn(board)) => Some(board.clone()),
+                Some(BoardChoice::Name(name)) => Some(named_board(&self.node, name)?),
+                None => None
+            };
+        }
+
+        // The build scripts run in the package's directory, so relative include directories are resolved here.
+        self.include_dirs = self.include_dirs.iter().map(|include_dir| current_dir.join(include_dir)).collect();
+        for include_dir in self.include_dirs.clone() {
+            if !include_dir.is_dir() {
+                self.warn(format_args!("Include directory '{}' does not exist", include_dir.display()))?;
+            }
+        }
+
+        // Menu options are merged into the board's parameters, whether the board was given as an option or in a file.
+        if !self.menus.is_empty() {
+            let mut board = self.target_board().cloned().map_or_else(|| {
+                Err(ErrorKind::InvalidArgument("'--menu' requires a target-board".to_string()))
+            }, Ok)?;
+            for &(ref key, ref value) in &self.menus {
+                board.set_param(key, value);
+            }
+            self.target_board = Some(board);
+        }
         Ok(())
     }
 
@@ -34,25 +127,25 @@ impl Config {
         while let Some(arg) = iter.next() {
             match arg.as_str() {
                 _ if arg.starts_with("--target=") => {
-                    self.shell.warn("Do not specify a target triple directly, instead use '--target-board'; option ignored")?;
+                    self.warn("Do not specify a target triple directly, instead use '--target-board'; option ignored")?;
                 }
                 "--target" => {
                     iter.next();
-                    self.shell.warn("Do not specify a target triple directly, instead use '--target-board'; option ignored")?;
+                    self.warn("Do not specify a target triple directly, instead use '--target-board'; option ignored")?;
                 }
 
-                option if arg.starts_with("--target-board=") => {
-                    let board = &option["--target-board=".len()..];
+                option if arg.starts_with("--target-board=") || arg.starts_with("-b=") => {
+                    let board = &option[option.find('=').unwrap() + 1..];
                     if board.is_empty() {
-                        bail!("target-board is empty");
+                        bail!(ErrorKind::InvalidArgument("target-board is empty".to_string()));
                     }
-                    self.target_board = Some(BoardInfo::from_fqbn(board)?);
+                    self.select_target_board(board)?;
                 }
-                "--target-board" => {
+                "--target-board" | "-b" => {
                     if let Some(board) = iter.next() {
-                        self.target_board = Some(BoardInfo::from_fqbn(&board)?);
+                        self.select_target_board(&board)?;
                     } else {
-                        bail!("Expected argument for option '--target-board'")
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--target-board'".to_string()))
                     }
                 }
 
@@ -83,7 +176,243 @@ impl Config {
                     }
                 }
 
+                option if arg.starts_with("--config=") => {
+                    self.config_overrides.push(option["--config=".len()..].to_string());
+                }
+                "--config" => {
+                    if let Some(value) = iter.next() {
+                        self.config_overrides.push(value);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--config'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--config-file=") => {
+                    self.config_file = Some(PathBuf::from(&option["--config-file=".len()..]));
+                }
+                "--config-file" => {
+                    if let Some(path) = iter.next() {
+                        self.config_file = Some(PathBuf::from(path));
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--config-file'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--manifest-path=") => {
+                    self.manifest_path = Some(PathBuf::from(&option["--manifest-path=".len()..]));
+                }
+                "--manifest-path" => {
+                    if let Some(path) = iter.next() {
+                        self.manifest_path = Some(PathBuf::from(path));
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--manifest-path'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--backend=") => {
+                    self.backend = Some(parse_backend(&option["--backend=".len()..])?);
+                }
+                "--backend" => {
+                    if let Some(backend) = iter.next() {
+                        self.backend = Some(parse_backend(&backend)?);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--backend'".to_string()))
+                    }
+                }
+
+                "--no-default-rustflags" => {
+                    self.default_rustflags = false;
+                }
+
+                "--carguino-json" => {
+                    self.json_messages = true;
+                }
+
+                "--explain" => {
+                    self.explain = true;
+                }
+
+                "--timings" => {
+                    self.timings = true;
+                }
+
+                "--two-pass" => {
+                    self.two_pass = true;
+                }
+
+                "--dump-config" => {
+                    self.dump_config = true;
+                }
+
+                "--dump-objects" => {
+                    self.dump_objects = true;
+                }
+
+                "--rebuild-core" => {
+                    self.rebuild_core = true;
+                }
+
+                option if arg.starts_with("--emit-spec=") => {
+                    self.emit_spec = Some(PathBuf::from(&option["--emit-spec=".len()..]));
+                }
+                "--emit-spec" => {
+                    if let Some(path) = iter.next() {
+                        self.emit_spec = Some(PathBuf::from(path));
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--emit-spec'".to_string()))
+                    }
+                }
+
+                "--disassemble" => {
+                    self.disassemble = Some(String::new());
+                }
+                option if arg.starts_with("--disassemble=") => {
+                    self.disassemble = Some(option["--disassemble=".len()..].to_string());
+                }
+
+                "--uf2" => {
+                    self.uf2 = true;
+                }
+
+                option if arg.starts_with("--uf2-family=") => {
+                    self.uf2_family = Some(parse_uf2_family(&option["--uf2-family=".len()..])?);
+                }
+                "--uf2-family" => {
+                    if let Some(family) = iter.next() {
+                        self.uf2_family = Some(parse_uf2_family(&family)?);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--uf2-family'".to_string()))
+                    }
+                }
+
+                "--strip" => {
+                    self.strip = true;
+                }
+
+                "--build-info" => {
+                    self.build_info = true;
+                }
+
+                option if arg.starts_with("--opt-level=") => {
+                    self.opt_level = Some(parse_opt_level(&option["--opt-level=".len()..])?);
+                }
+                "--opt-level" => {
+                    if let Some(level) = iter.next() {
+                        self.opt_level = Some(parse_opt_level(&level)?);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--opt-level'".to_string()))
+                    }
+                }
+
+                "--no-gc-sections" => {
+                    self.gc_sections = false;
+                }
+
+                option if arg.starts_with("--link-arg=") => {
+                    self.link_args.push(option["--link-arg=".len()..].to_string());
+                }
+                "--link-arg" => {
+                    if let Some(link_arg) = iter.next() {
+                        self.link_args.push(link_arg);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--link-arg'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--defsym=") => {
+                    self.defsyms.push(parse_defsym(&option["--defsym=".len()..])?);
+                }
+                "--defsym" => {
+                    if let Some(symbol) = iter.next() {
+                        self.defsyms.push(parse_defsym(&symbol)?);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--defsym'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--menu=") => {
+                    self.menus.push(parse_menu(&option["--menu=".len()..])?);
+                }
+                "--menu" => {
+                    if let Some(menu) = iter.next() {
+                        self.menus.push(parse_menu(&menu)?);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--menu'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--serial-port=") => {
+                    self.serial_port = Some(option["--serial-port=".len()..].to_string());
+                }
+                "--serial-port" => {
+                    if let Some(port) = iter.next() {
+                        self.serial_port = Some(port);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--serial-port'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--upload-retries=") => {
+                    self.upload_retries = parse_number("--upload-retries", &option["--upload-retries=".len()..])?;
+                }
+                "--upload-retries" => {
+                    if let Some(retries) = iter.next() {
+                        self.upload_retries = parse_number("--upload-retries", &retries)?;
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--upload-retries'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--upload-timeout=") => {
+                    let seconds = parse_number("--upload-timeout", &option["--upload-timeout=".len()..])?;
+                    self.upload_timeout = Duration::from_secs(seconds as u64);
+                }
+                "--upload-timeout" => {
+                    if let Some(seconds) = iter.next() {
+                        let seconds = parse_number("--upload-timeout", &seconds)?;
+                        self.upload_timeout = Duration::from_secs(seconds as u64);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--upload-timeout'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--include-dir=") => {
+                    self.include_dirs.push(PathBuf::from(&option["--include-dir=".len()..]));
+                }
+                "--include-dir" => {
+                    if let Some(include_dir) = iter.next() {
+                        self.include_dirs.push(PathBuf::from(include_dir));
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--include-dir'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--linker=") => {
+                    self.linker = Some(PathBuf::from(&option["--linker=".len()..]));
+                }
+                "--linker" => {
+                    if let Some(linker) = iter.next() {
+                        self.linker = Some(PathBuf::from(linker));
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--linker'".to_string()))
+                    }
+                }
+
+                option if arg.starts_with("--post-build=") => {
+                    self.post_build_command = Some(option["--post-build=".len()..].to_string());
+                }
+                "--post-build" => {
+                    if let Some(command) = iter.next() {
+                        self.post_build_command = Some(command);
+                    } else {
+                        bail!(ErrorKind::InvalidArgument("Expected argument for option '--post-build'".to_string()))
+                    }
+                }
+
                 "--verbose" | "-v" | "-vv" => {
+                    if arg == "-vv" || self.shell.get_verbose() == Verbosity::Verbose {
+                        self.extra_verbose = true;
+                    }
                     self.shell.set_verbosity(Verbosity::Verbose);
                     cargo_args.push(arg.clone());
                 }
@@ -92,6 +421,12 @@ impl Config {
                     cargo_args.push(arg.clone());
                 }
 
+                // The arguments after `--` are for rustc or the program, so none of them are options for carguino.
+                "--" => {
+                    cargo_args.push(arg.clone());
+                    cargo_args.extend(iter.by_ref());
+                }
+
                 _ => {
                     cargo_args.push(arg.clone())
                 }
@@ -100,6 +435,37 @@ impl Config {
         Ok(cargo_args)
     }
 
+    pub fn json_message_format(&self) -> bool {
+        match self.message_format {
+            MessageFormat::Json => true,
+            MessageFormat::Human => false
+        }
+    }
+
+    // The detected board's port is also used for uploading, unless another one was given.
+    fn detect_board(&mut self) -> Result<BoardInfo> {
+        let mut hardware_dirs = self.home().map(|home| home.join("hardware")).into_iter().collect::<Vec<_>>();
+        hardware_dirs.extend(self.node.hardware().into_iter().map(Path::to_path_buf));
+        let (board, port) = serial::detect_board(&board::installed_boards(&hardware_dirs)?)?;
+        self.status("Detected", format!("board {} on serial port {}", board, port))?;
+        if self.serial_port.as_ref().map_or(true, |serial_port| serial_port == "auto") {
+            self.serial_port = Some(port);
+        }
+        Ok(board)
+    }
+
+    // Names without colons refer to one of the `[[boards]]` in the configuration, which is only read later.
+    fn select_target_board(&mut self, board: &str) -> Result<()> {
+        if board.contains(':') {
+            self.target_board = Some(parse_target_board(board)?);
+            self.target_board_name = None;
+        } else {
+            self.target_board = None;
+            self.target_board_name = Some(board.to_string());
+        }
+        Ok(())
+    }
+
     pub fn add_message_format_option<'a>(&self, builder: &'a mut ProcessBuilder) -> &'a mut ProcessBuilder {
         builder.arg("--message-format");
         match self.message_format {
@@ -108,56 +474,500 @@ impl Config {
         }
     }
 
+    pub fn add_config_options<'a>(&self, builder: &'a mut ProcessBuilder) -> &'a mut ProcessBuilder {
+        if let Some(ref manifest_path) = self.manifest_path {
+            builder.arg("--manifest-path").arg(manifest_path);
+        }
+        for value in &self.config_overrides {
+            builder.arg("--config").arg(value);
+        }
+        builder
+    }
+
     pub fn shell(&mut self) -> &mut MultiShell {
         &mut self.shell
     }
 
+    pub fn is_verbose(&self) -> bool {
+        self.shell.get_verbose() == Verbosity::Verbose
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.shell.get_verbose() == Verbosity::Quiet
+    }
+
+    pub fn status<T: Display, U: Display>(&mut self, status: T, message: U) -> Result<()> {
+        self.say_status("info", status, message)
+    }
+
+    pub fn verbose_status<T: Display, U: Display>(&mut self, status: T, message: U) -> Result<()> {
+        if self.is_verbose() {
+            self.say_status("verbose", status, message)?;
+        }
+        Ok(())
+    }
+
+    pub fn explain<T: Display>(&mut self, explanation: T) -> Result<()> {
+        if self.explain {
+            let phase = self.phase.clone();
+            self.say_status("explain", "Explaining", explanation)?;
+            self.phase = phase;
+        }
+        Ok(())
+    }
+
+    pub fn warn<T: Display>(&mut self, message: T) -> Result<()> {
+        if self.json_messages {
+            self.say_json("warning", message)
+        } else if self.shell.warn(&message).is_err() {
+            // Fall back to plain text if the terminal could not be written to with colors.
+            writeln!(io::stderr(), "warning: {}", message).chain_err(|| "Unable to write message")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn error<T: Display>(&mut self, message: T) -> Result<()> {
+        if self.json_messages {
+            self.say_json("error", message)
+        } else if self.shell.error(&message).is_err() {
+            writeln!(io::stderr(), "error: {}", message).chain_err(|| "Unable to write message")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn say_status<T: Display, U: Display>(&mut self, level: &str, status: T, message: U) -> Result<()> {
+        self.phase = status.to_string();
+        if self.shell.get_verbose() == Verbosity::Quiet {
+            Ok(())
+        } else if self.json_messages {
+            self.say_json(level, message)
+        } else if self.shell.err().say_status(&status, &message, color::CYAN, true).is_err() {
+            writeln!(io::stderr(), "{:>12} {}", status, message).chain_err(|| "Unable to write message")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn say_json<T: Display>(&self, level: &str, message: T) -> Result<()> {
+        let message = JsonMessage {
+            phase: &self.phase,
+            level: level,
+            message: message.to_string()
+        };
+        writeln!(io::stderr(), "{}", serde_json::to_string(&message).unwrap()).chain_err(|| "Unable to write message")
+    }
+
+    pub fn default_rustflags(&self) -> bool {
+        self.default_rustflags
+    }
+
+    pub fn extra_verbose(&self) -> bool {
+        self.extra_verbose
+    }
+
+    pub fn timings(&self) -> bool {
+        self.timings
+    }
+
+    pub fn defsyms(&self) -> &[String] {
+        &self.defsyms
+    }
+
+    pub fn opt_level(&self) -> Option<&str> {
+        self.opt_level.as_ref().map(String::as_str)
+    }
+
+    pub fn gc_sections(&self) -> bool {
+        self.gc_sections
+    }
+
+    pub fn link_args(&self) -> &[String] {
+        &self.link_args
+    }
+
+    pub fn disassemble(&self) -> Option<&str> {
+        self.disassemble.as_ref().map(String::as_str)
+    }
+
+    pub fn two_pass(&self) -> bool {
+        self.two_pass
+    }
+
+    pub fn uf2(&self) -> bool {
+        self.uf2
+    }
+
+    pub fn uf2_family(&self) -> Option<u32> {
+        self.uf2_family
+    }
+
+    pub fn strip(&self) -> bool {
+        self.strip
+    }
+
+    pub fn build_info(&self) -> bool {
+        self.build_info
+    }
+
+    pub fn manifest_path(&self) -> Option<&Path> {
+        self.manifest_path.as_ref().map(PathBuf::as_path)
+    }
+
+    pub fn dump_config(&self) -> bool {
+        self.dump_config
+    }
+
+    pub fn dump_objects(&self) -> bool {
+        self.dump_objects
+    }
+
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+
+    pub fn rebuild_core(&self) -> bool {
+        self.rebuild_core
+    }
+
+    pub fn emit_spec(&self) -> Option<&Path> {
+        self.emit_spec.as_ref().map(PathBuf::as_path)
+    }
+
+    pub fn effective_config(&self) -> Result<String> {
+        let home = self.home();
+        let config = EffectiveConfig {
+            target_board: self.target_board().map(ToString::to_string),
+            defaults: EffectiveDefaults {
+                args: self.node.default_args().unwrap_or(&[])
+            },
+            arduino_builder: EffectiveArduinoBuilder {
+                backend: self.backend(),
+                home: home.as_ref().map(PathBuf::as_path),
+                hardware: self.node.hardware(),
+                tools: self.node.tools(),
+                libraries: self.node.libraries(),
+                preferences_files: self.node.preferences_files(),
+                tools_builder: self.node.tools_builder(),
+                avr_tools: self.node.avr_tools(),
+                archiver_override: self.archiver_override(),
+                linker: self.linker(),
+                temp_dir: self.temp_dir(),
+                variant_includes_first: self.variant_includes_first(),
+                post_build_command: self.post_build_command(),
+                // Collecting into a map keeps the last, i.e. overriding, value of each key.
+                preferences: self.node.preferences().into_iter().collect(),
+                recipe_overrides: self.node.recipe_overrides().into_iter().collect()
+            },
+            feature_defines: self.node.feature_defines().into_iter().collect()
+        };
+        toml::to_string(&config).chain_err(|| "Could not serialize the effective configuration")
+    }
+
     pub fn target_board(&self) -> Option<&BoardInfo> {
-        self.target_board.as_ref().or_else(|| self.node.target_board())
+        self.target_board.as_ref()
     }
 
-    pub fn create_builder(&self) -> Option<Builder> {
-        self.target_board().map(|board| {
-            let mut builder = Builder::new(board);
+    pub fn backend(&self) -> &str {
+        self.backend.as_ref().map(String::as_str).or_else(|| self.node.backend()).unwrap_or("auto")
+    }
 
-            let home_var = env::var_os("ARDUINO_HOME").map(PathBuf::from);
-            if let Some(home) = home_var.as_ref().map(PathBuf::as_path).or_else(|| self.node.home()) {
-                builder.home(home);
-            }
+    pub fn libraries(&self) -> Vec<PathBuf> {
+        self.node.libraries().into_iter().map(PathBuf::from).collect()
+    }
 
-            for hardware in self.node.hardware() {
-                builder.hardware(hardware);
-            }
+    pub fn archiver_override(&self) -> Option<&Path> {
+        self.node.archiver_override()
+    }
 
-            for tools in self.node.tools() {
-                builder.tools(tools);
-            }
+    pub fn recipe_overrides(&self) -> HashMap<String, String> {
+        self.node.recipe_overrides().into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
 
-            for libraries in self.node.libraries() {
-                builder.libraries(libraries);
-            }
+    // Like the preferences, the mapping of a feature in one file overrides the one in the files it is nested in.
+    pub fn feature_defines(&self) -> BTreeMap<String, String> {
+        self.node.feature_defines().into_iter().map(|(feature, define)| {
+            (feature.to_string(), define.to_string())
+        }).collect()
+    }
+
+    pub fn upload_retries(&self) -> u32 {
+        self.upload_retries
+    }
 
-            for (key, value) in self.node.preferences() {
-                builder.pref(key, value);
+    pub fn upload_timeout(&self) -> Duration {
+        self.upload_timeout
+    }
+
+    pub fn serial_port(&self) -> Option<&str> {
+        self.serial_port.as_ref().map(String::as_str)
+    }
+
+    // A default is left out when the same option is given on the command line, so the command line takes precedence.
+    pub fn with_default_args(&self, command: &str, args: Vec<String>) -> Vec<String> {
+        let defaults = match self.node.default_args() {
+            Some(defaults) if DEFAULT_ARGS_COMMANDS.contains(&command) => defaults,
+            _ => return args
+        };
+
+        let option_name = |arg: &str| arg.split('=').next().unwrap().to_string();
+        let mut merged = Vec::new();
+        let mut iter = defaults.iter().peekable();
+        while let Some(arg) = iter.next() {
+            let mut group = vec![arg.clone()];
+            if arg.starts_with('-') && !arg.contains('=') && iter.peek().map_or(false, |next| !next.starts_with('-')) {
+                group.push(iter.next().unwrap().clone());
+            }
+            let mut cli_options = args.iter().take_while(|cli_arg| *cli_arg != "--");
+            if !arg.starts_with('-') || !cli_options.any(|cli_arg| option_name(cli_arg) == option_name(arg)) {
+                merged.extend(group);
             }
+        }
+        merged.extend(args);
+        merged
+    }
 
-            builder
-        })
+    pub fn linker(&self) -> Option<&Path> {
+        self.linker.as_ref().map(PathBuf::as_path).or_else(|| self.node.linker())
+    }
+
+    pub fn post_build_command(&self) -> Option<&str> {
+        self.post_build_command.as_ref().map(String::as_str).or_else(|| self.node.post_build_command())
+    }
+
+    pub fn variant_includes_first(&self) -> bool {
+        self.node.variant_includes_first().unwrap_or(false)
+    }
+
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.node.temp_dir()
+    }
+
+    pub fn home(&self) -> Option<PathBuf> {
+        env::var_os("ARDUINO_HOME").map(PathBuf::from).or_else(|| self.node.home().map(PathBuf::from))
+    }
+
+    pub fn create_builder(&self) -> Result<Option<Builder>> {
+        let board = match self.target_board() {
+            Some(board) => board,
+            None => return Ok(None)
+        };
+        let mut builder = Builder::new(board);
+
+        let home = self.home();
+        if let Some(ref home) = home {
+            builder.home(home.clone());
+        }
+
+        let backend = self.backend();
+        let backend = Backend::from_name(backend).map_or_else(|| Err(format!("Unknown backend '{}'", backend)), Ok)?;
+        builder.backend(backend.unwrap_or_else(|| Backend::detect(home.as_ref().map(PathBuf::as_path))));
+
+        for hardware in self.node.hardware() {
+            builder.hardware(hardware);
+        }
+
+        for tools in self.node.tools() {
+            builder.tools(tools);
+        }
+
+        if let Some(path) = self.node.tools_builder() {
+            builder.tools_builder(path);
+        }
+
+        if let Some(path) = self.node.avr_tools() {
+            builder.avr_tools(path);
+        }
+
+        for libraries in self.node.libraries() {
+            builder.libraries(libraries);
+        }
+
+        for file in self.node.preferences_files() {
+            builder.prefs_file(file)?;
+        }
+
+        for (key, value) in self.node.preferences() {
+            builder.pref(key, value);
+        }
+
+        Ok(Some(builder))
     }
 }
 
+fn parse_defsym(symbol: &str) -> Result<String> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r#"^[A-Za-z_.$][\w.$]*=\S+$"#).unwrap();
+    }
+    if REGEX.is_match(symbol) {
+        Ok(symbol.to_string())
+    } else {
+        bail!(ErrorKind::InvalidArgument(format!("Expected a symbol definition of the form KEY=VALUE, found '{}'", symbol)))
+    }
+}
+
+fn parse_backend(backend: &str) -> Result<String> {
+    if Backend::from_name(backend).is_some() {
+        Ok(backend.to_string())
+    } else {
+        bail!(ErrorKind::InvalidArgument(format!("Unknown backend '{}' (expected `auto`, `arduino-builder` or `arduino-cli`)",
+                                                 backend)))
+    }
+}
+
+fn parse_uf2_family(family: &str) -> Result<u32> {
+    uf2::family_id(family).map_or_else(|| {
+        Err(ErrorKind::InvalidArgument(format!("Unknown UF2 family '{}' (expected a name or a hexadecimal ID)", family)).into())
+    }, Ok)
+}
+
+fn parse_opt_level(level: &str) -> Result<String> {
+    match level {
+        "0" | "1" | "2" | "3" | "s" | "z" => Ok(level.to_string()),
+        _ => bail!(ErrorKind::InvalidArgument(format!("Expected `0`, `1`, `2`, `3`, `s` or `z` for option '--opt-level', \
+                                                       found '{}'", level)))
+    }
+}
+
+fn parse_menu(menu: &str) -> Result<(String, String)> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r#"^([^\s=,:]+)=([^\s=,:]+)$"#).unwrap();
+    }
+    REGEX.captures(menu).map(|captures| (captures[1].to_string(), captures[2].to_string())).map_or_else(|| {
+        Err(ErrorKind::InvalidArgument(format!("Expected a menu option of the form KEY=VALUE, found '{}'", menu)).into())
+    }, Ok)
+}
+
+fn parse_number(option: &str, value: &str) -> Result<u32> {
+    value.parse().map_err(|_| {
+        ErrorKind::InvalidArgument(format!("Expected a number for option '{}', found '{}'", option, value)).into()
+    })
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    let mut config = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut config)).chain_err(|| {
+        format!("Could not read configuration file '{}'", path.display())
+    })?;
+    toml::from_str(&config).chain_err(|| format!("Could not parse configuration file '{}'", path.display()))
+}
+
+fn named_board(node: &ConfigNode, name: &str) -> Result<BoardInfo> {
+    match node.board_fqbn(name) {
+        Some(fqbn) => {
+            BoardInfo::from_fqbn(fqbn).chain_err(|| format!("Invalid FQBN of board '{}' in the configuration", name))
+        }
+        None => {
+            let names = node.board_names();
+            bail!(ErrorKind::InvalidArgument(format!("No board named '{}' in the configuration (boards: {})", name,
+                                                     if names.is_empty() { "none".to_string() } else { names.join(", ") })))
+        }
+    }
+}
+
+fn parse_target_board(fqbn: &str) -> Result<BoardInfo> {
+    BoardInfo::from_fqbn(fqbn).map_err(|error| ErrorKind::InvalidArgument(error.to_string()).into())
+}
+
 impl Default for Config {
     fn default() -> Config {
+        // See https://no-color.org; an explicit `--color` option still takes precedence.
+        let color_config = if env::var_os("NO_COLOR").is_some() { ColorConfig::Never } else { ColorConfig::Auto };
         Config {
             node: Default::default(),
-            shell: cargo::shell(Verbosity::Normal, ColorConfig::Auto),
+            backend: None,
+            build_info: false,
+            config_file: None,
+            config_overrides: Vec::new(),
+            default_rustflags: true,
+            defsyms: Vec::new(),
+            gc_sections: true,
+            link_args: Vec::new(),
+            linker: None,
+            disassemble: None,
+            dump_config: false,
+            dump_objects: false,
+            emit_spec: None,
+            explain: false,
+            extra_verbose: false,
+            include_dirs: Vec::new(),
+            json_messages: false,
+            manifest_path: None,
+            menus: Vec::new(),
+            opt_level: None,
+            phase: String::new(),
+            shell: cargo::shell(Verbosity::Normal, color_config),
             message_format: MessageFormat::Human,
-            target_board: None
+            post_build_command: None,
+            rebuild_core: false,
+            serial_port: None,
+            strip: false,
+            upload_retries: 0,
+            upload_timeout: Duration::from_secs(10),
+            target_board: None,
+            target_board_name: None,
+            timings: false,
+            two_pass: false,
+            uf2: false,
+            uf2_family: None
         }
     }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    #[serde(rename = "target-board", skip_serializing_if = "Option::is_none")]
+    target_board: Option<String>,
+    defaults: EffectiveDefaults<'a>,
+    #[serde(rename = "arduino-builder")]
+    arduino_builder: EffectiveArduinoBuilder<'a>,
+    #[serde(rename = "feature-defines")]
+    feature_defines: BTreeMap<&'a str, &'a str>
+}
+
+#[derive(Serialize)]
+struct EffectiveDefaults<'a> {
+    args: &'a [String]
+}
+
+#[derive(Serialize)]
+struct EffectiveArduinoBuilder<'a> {
+    backend: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    home: Option<&'a Path>,
+    hardware: Vec<&'a Path>,
+    tools: Vec<&'a Path>,
+    libraries: Vec<&'a Path>,
+    #[serde(rename = "preferences-files")]
+    preferences_files: Vec<&'a Path>,
+    #[serde(rename = "tools-builder", skip_serializing_if = "Option::is_none")]
+    tools_builder: Option<&'a Path>,
+    #[serde(rename = "avr-tools", skip_serializing_if = "Option::is_none")]
+    avr_tools: Option<&'a Path>,
+    #[serde(rename = "archiver-override", skip_serializing_if = "Option::is_none")]
+    archiver_override: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linker: Option<&'a Path>,
+    #[serde(rename = "temp-dir", skip_serializing_if = "Option::is_none")]
+    temp_dir: Option<&'a Path>,
+    #[serde(rename = "variant-includes-first")]
+    variant_includes_first: bool,
+    #[serde(rename = "post-build-command", skip_serializing_if = "Option::is_none")]
+    post_build_command: Option<&'a str>,
+    preferences: BTreeMap<&'a str, &'a str>,
+    #[serde(rename = "recipe-overrides")]
+    recipe_overrides: BTreeMap<&'a str, &'a str>
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    phase: &'a str,
+    level: &'a str,
+    message: String
+}
+
+#[derive(Debug, Default)]
 pub struct ConfigNode {
     parent: Option<Box<ConfigNode>>,
     config: ConfigFile
@@ -171,39 +981,110 @@ impl ConfigNode {
             (env::home_dir(), Box::new(ConfigNode::default()))
         };
 
-        path.map(|path| path.join(".carguino/config")).and_then(|path| {
-            if path.is_file() { Some(path) } else { None }
-        }).map(|path| {
-            File::open(&path).and_then(|mut file| {
-                let mut config = String::new();
-                file.read_to_string(&mut config).map(|_| config)
-            }).chain_err(|| {
-                format!("Could not read configuration file '{}'", path.display())
-            }).and_then(|config| {
-                toml::from_str(&config).map(|config| {
-                    ConfigNode {
-                        parent: Some(parent.clone()),
-                        config: config
-                    }
-                }).map(Box::new).chain_err(|| {
-                    format!("Could not parse configuration file '{}'", path.display())
-                })
-            })
-        }).unwrap_or_else(|| Ok(parent))
-    }
-
-    fn target_board(&self) -> Option<&BoardInfo> {
-        self.config.target_board.as_ref().or_else(|| {
-            self.parent.as_ref().and_then(|parent| parent.target_board())
+        let config = match path.map(|path| path.join(".carguino/config")) {
+            Some(ref path) if path.is_file() => Some(read_config_file(path)?),
+            _ => None
+        };
+
+        // The parent is moved into its child, so every node is only created once.
+        Ok(match config {
+            Some(config) => Box::new(ConfigNode {
+                parent: Some(parent),
+                config: config
+            }),
+            None => parent
+        })
+    }
+
+    // An explicit configuration file is the child of all others, so its settings take precedence.
+    fn with_file(parent: Box<ConfigNode>, path: &Path) -> Result<Box<ConfigNode>> {
+        Ok(Box::new(ConfigNode {
+            parent: Some(parent),
+            config: read_config_file(path)?
+        }))
+    }
+
+    // The nearest configuration that selects a board decides, either by FQBN or by the name of one of the `[[boards]]`.
+    fn board_choice(&self) -> Option<BoardChoice> {
+        match (self.config.target_board.as_ref(), self.config.default_board.as_ref()) {
+            (Some(board), _) => Some(BoardChoice::Fqbn(board)),
+            (None, Some(name)) => Some(BoardChoice::Name(name)),
+            (None, None) => self.parent.as_ref().and_then(|parent| parent.board_choice())
+        }
+    }
+
+    fn board_fqbn(&self, name: &str) -> Option<&str> {
+        self.config.boards.iter().find(|board| board.name == name).map(|board| board.fqbn.as_str()).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.board_fqbn(name))
         })
     }
 
+    fn board_names(&self) -> Vec<&str> {
+        self.parent.iter().flat_map(|parent| parent.board_names()).chain(
+            self.config.boards.iter().map(|board| board.name.as_str())
+        ).collect()
+    }
+
     fn home(&self) -> Option<&Path> {
         self.config.arduino_builder.home.as_ref().map(PathBuf::as_path).or_else(|| {
             self.parent.as_ref().and_then(|parent| parent.home())
         })
     }
 
+    fn backend(&self) -> Option<&str> {
+        self.config.arduino_builder.backend.as_ref().map(String::as_str).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.backend())
+        })
+    }
+
+    fn tools_builder(&self) -> Option<&Path> {
+        self.config.arduino_builder.tools_builder.as_ref().map(PathBuf::as_path).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.tools_builder())
+        })
+    }
+
+    fn avr_tools(&self) -> Option<&Path> {
+        self.config.arduino_builder.avr_tools.as_ref().map(PathBuf::as_path).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.avr_tools())
+        })
+    }
+
+    fn archiver_override(&self) -> Option<&Path> {
+        self.config.arduino_builder.archiver_override.as_ref().map(PathBuf::as_path).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.archiver_override())
+        })
+    }
+
+    fn linker(&self) -> Option<&Path> {
+        self.config.arduino_builder.linker.as_ref().map(PathBuf::as_path).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.linker())
+        })
+    }
+
+    fn default_args(&self) -> Option<&[String]> {
+        self.config.defaults.as_ref().map(|defaults| defaults.args.as_slice()).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.default_args())
+        })
+    }
+
+    fn post_build_command(&self) -> Option<&str> {
+        self.config.arduino_builder.post_build_command.as_ref().map(String::as_str).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.post_build_command())
+        })
+    }
+
+    fn variant_includes_first(&self) -> Option<bool> {
+        self.config.arduino_builder.variant_includes_first.or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.variant_includes_first())
+        })
+    }
+
+    fn temp_dir(&self) -> Option<&Path> {
+        self.config.arduino_builder.temp_dir.as_ref().map(PathBuf::as_path).or_else(|| {
+            self.parent.as_ref().and_then(|parent| parent.temp_dir())
+        })
+    }
+
     fn hardware(&self) -> Vec<&Path> {
         self.parent.iter().flat_map(|parent| parent.hardware()).chain(
             self.config.arduino_builder.hardware.iter().map(PathBuf::as_path)
@@ -222,11 +1103,29 @@ impl ConfigNode {
         ).collect()
     }
 
+    fn recipe_overrides(&self) -> Vec<(&str, &str)> {
+        self.parent.iter().flat_map(|parent| parent.recipe_overrides()).chain(
+            self.config.arduino_builder.recipe_overrides.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+        ).collect()
+    }
+
+    fn preferences_files(&self) -> Vec<&Path> {
+        self.parent.iter().flat_map(|parent| parent.preferences_files()).chain(
+            self.config.arduino_builder.preferences_file.as_ref().map(PathBuf::as_path)
+        ).collect()
+    }
+
     fn preferences(&self) -> Vec<(&str, &str)> {
         self.parent.iter().flat_map(|parent| parent.preferences()).chain(
             self.config.arduino_builder.preferences.iter().map(|(key, value)| (key.as_str(), value.as_str()))
         ).collect()
     }
+
+    fn feature_defines(&self) -> Vec<(&str, &str)> {
+        self.parent.iter().flat_map(|parent| parent.feature_defines()).chain(
+            self.config.feature_defines.iter().map(|(feature, define)| (feature.as_str(), define.as_str()))
+        ).collect()
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -234,17 +1133,61 @@ impl ConfigNode {
 struct ConfigFile {
     #[serde(rename = "target-board")]
     target_board: Option<BoardInfo>,
+    #[serde(rename = "default-board")]
+    default_board: Option<String>,
+    #[serde(default)]
+    boards: Vec<NamedBoard>,
     #[serde(default, rename = "arduino-builder")]
-    arduino_builder: ArduinoBuilder
+    arduino_builder: ArduinoBuilder,
+    defaults: Option<Defaults>,
+    #[serde(default, rename = "feature-defines")]
+    feature_defines: HashMap<String, String>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamedBoard {
+    name: String,
+    fqbn: String
+}
+
+enum BoardChoice<'a> {
+    Fqbn(&'a BoardInfo),
+    Name(&'a str)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    #[serde(default)]
+    args: Vec<String>
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct ArduinoBuilder {
+    backend: Option<String>,
     home: Option<PathBuf>,
     hardware: Vec<PathBuf>,
     tools: Vec<PathBuf>,
     libraries: Vec<PathBuf>,
     #[serde(default)]
-    preferences: HashMap<String, String>
+    preferences: HashMap<String, String>,
+    #[serde(rename = "preferences-file")]
+    preferences_file: Option<PathBuf>,
+    #[serde(default, rename = "recipe-overrides")]
+    recipe_overrides: HashMap<String, String>,
+    #[serde(rename = "archiver-override")]
+    archiver_override: Option<PathBuf>,
+    linker: Option<PathBuf>,
+    #[serde(rename = "tools-builder")]
+    tools_builder: Option<PathBuf>,
+    #[serde(rename = "avr-tools")]
+    avr_tools: Option<PathBuf>,
+    #[serde(rename = "temp-dir")]
+    temp_dir: Option<PathBuf>,
+    #[serde(rename = "variant-includes-first")]
+    variant_includes_first: Option<bool>,
+    #[serde(rename = "post-build-command")]
+    post_build_command: Option<String>
 }
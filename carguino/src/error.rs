@@ -7,6 +7,18 @@ error_chain! {
         Docopt(::docopt::Error);
         Cargo(Box<::cargo::CargoError>);
     }
+
+    errors {
+        Compilation(error: ::cargo::util::ProcessError) {
+            description("compilation failed")
+            display("{}", error)
+        }
+
+        InvalidArgument(message: String) {
+            description("invalid argument")
+            display("{}", message)
+        }
+    }
 }
 
 impl From<::cargo::util::ProcessError> for Error {
@@ -14,3 +26,12 @@ impl From<::cargo::util::ProcessError> for Error {
         ErrorKind::Cargo(Box::new(error)).into()
     }
 }
+
+// A cargo or xargo that ran and failed did not compile; one that could not be run at all is a tool error.
+pub fn compilation_error(error: ::cargo::util::ProcessError) -> Error {
+    if error.exit.is_some() {
+        ErrorKind::Compilation(error).into()
+    } else {
+        error.into()
+    }
+}
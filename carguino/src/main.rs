@@ -2,7 +2,10 @@ extern crate cargo;
 extern crate carguino_build;
 extern crate docopt;
 #[macro_use] extern crate error_chain;
+#[cfg(windows)] extern crate kernel32;
 #[macro_use] extern crate lazy_static;
+extern crate notify;
+extern crate num_cpus;
 extern crate regex;
 extern crate rustc_serialize;
 #[macro_use] extern crate serde_derive;
@@ -12,53 +15,53 @@ extern crate term;
 extern crate toml;
 
 use board::BoardInfo;
+use builder::{self, Backend, Builder};
 use config::Config;
-use error::{Result, ResultExt};
+use error::{Error, ErrorKind, Result, ResultExt};
+use timings::Timings;
 
-use cargo::CargoResult;
-use cargo::core::{MultiShell, Verbosity};
-use cargo::util;
+use cargo::util::{self, Cfg, ProcessBuilder};
 
+use carguino_build::Preferences;
 use carguino_build::config as build_config;
 
 use docopt::Docopt;
 
-use regex::Regex;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use regex::{Captures, Regex};
 
 use serde_json::Value;
 
 use tempdir::TempDir;
 
-use term::color;
-
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
-use std::fmt::Display;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Cursor, Write};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 mod board;
 mod builder;
+mod cache;
+mod cargo_config;
 mod config;
 mod error;
+mod serial;
+mod timings;
+mod uf2;
 
-const VERSION_STRING: &'static str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
-
-trait MultiShellExt {
-    fn status_ext<T: Display, U: Display>(&mut self, status: T, message: U) -> CargoResult<()>;
-}
+// Editors often save a file in several steps, which should only cause a single rebuild.
+const WATCH_DEBOUNCE_MS: u64 = 500;
 
-impl MultiShellExt for MultiShell {
-    fn status_ext<T: Display, U: Display>(&mut self, status: T, message: U) -> CargoResult<()> {
-        if self.get_verbose() != Verbosity::Quiet {
-            self.err().say_status(status, message, color::CYAN, true)?;
-        }
-        Ok(())
-    }
-}
+const VERSION_STRING: &'static str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
 
 const USAGE: &'static str = "
 Cargo wrapper for Arduino projects.
@@ -69,30 +72,174 @@ Usage:
     carguino -V | --version
 
 Options:
-    --target-board BOARD   Fully-qualified Arduino board name to compile for
-    --serial-port PORT     Serial port to upload to
+    -b, --target-board BOARD
+                           Fully-qualified Arduino board name to compile for,
+                           or the name of one of the `[[boards]]` configured,
+                           or `auto` to detect the connected board
+    --menu KEY=VALUE       Select a board menu option, such as `cpu=atmega328`
+                           (may be repeated; see `carguino menus`)
+    --serial-port PORT     Serial port to upload to, or `auto` to detect the port
+                           the board is connected to
+    --timings              Write a report of the time spent in each build phase
+                           to `<target-dir>/carguino-timings`
+    --backend BACKEND      Tool to retrieve the board's build settings from:
+                           `arduino-builder`, `arduino-cli` or `auto` (the
+                           default), which prefers arduino-builder
+    --manifest-path PATH   Path to the project's Cargo.toml; the project's
+                           configuration is also read from its directory
+    --config-file PATH     Read the configuration from PATH as well, with its
+                           settings taking precedence over all other files
+    --no-default-rustflags Do not add the architecture's default RUSTFLAGS
+    --carguino-json        Print carguino's own messages as JSON objects with
+                           `phase`, `level` and `message` fields to stderr
+    --explain              Explain why each external command is run
+    --dump-config          Print the effective configuration, merged from all
+                           configuration files and options, as TOML and exit
+    --dump-objects         List the object files in each archive the build
+                           script builds, such as the core, and its path
+    --rebuild-core         Compile the board's core again, e.g. after changing
+                           the platform's headers, without cleaning the Rust
+                           build
+    --emit-spec PATH       Write the board's target specification to PATH and
+                           exit without running the command, e.g. to build with
+                           `cargo build -Z build-std --target PATH` later
+    --include-dir PATH     Also search PATH for headers when compiling the core
+                           and libraries or generating bindings (may be
+                           repeated)
+    --defsym KEY=VALUE     Define a symbol when linking (may be repeated)
+    --link-arg ARG         Pass ARG to the linker as-is (may be repeated)
+    --opt-level LEVEL      Optimization level for both Rust and C/C++ code: `0`,
+                           `1`, `2`, `3`, `s` or `z` (C/C++ code uses `s` for `z`)
+    --no-gc-sections       Keep unused functions and data when linking
+    --two-pass             Build with cargo's usual messages first, then repeat
+                           the build with JSON messages to find the binaries
+    --linker PATH          Link with PATH instead of the platform's linker, such
+                           as a wrapper script or `ld.lld`
+    --disassemble          Also write a disassembly listing of each binary to
+                           `<binary>.lst`; use `--disassemble=FLAGS` to pass
+                           extra flags on to objdump
+    --uf2                  Also convert each binary to `<binary>.uf2` for boards
+                           with a UF2 bootloader
+    --uf2-family FAMILY    UF2 family of the board, by name (`SAMD21`, `SAMD51`,
+                           `NRF52840`, `RP2040` or `STM32F4`) or as a hexadecimal
+                           ID; by default it is taken from the `build.uf2_family`
+                           preference or derived from the board's architecture
+    --build-info           Also write the board, profile, rustc version, RUSTFLAGS
+                           and git commit of each binary to
+                           `<binary>.buildinfo.json`
+    --strip                Also write a copy of each binary without symbols to
+                           `<binary>.stripped.elf`
+    --post-build CMD       Run CMD for each binary after a successful build
+    --upload-retries N     Retry a failing post-build command up to N times
+                           [default: 0]
+    --upload-timeout SECS  Time to wait for the bootloader's serial port to
+                           appear after resetting the board [default: 10]
     -h, --help             Show this message
     -V, --version          Print version info and exit
 
 The supported cargo subcommands are: `build`, `check`, `clean`, `doc`, `rustc`,
 `rustdoc` and `clippy` (if installed). Any other commands are passed as-is to
 cargo.
+
+The build runs once with JSON messages, from which carguino finds the binaries
+and prints rustc's diagnostics as cargo would. With `--two-pass`, the build
+runs with cargo's usual messages first and is then repeated (without compiling
+anything again) to collect the JSON messages, e.g. for a rustc that does not
+include the rendered text of its diagnostics in its JSON messages.
+
+With `--message-format json`, cargo's JSON messages are printed to stdout as
+they arrive; carguino's own messages go to stderr.
+
+`doc` and `rustdoc` document the crate for the board's target with the same
+cfgs as a build; doctests can not be run on the board and are not supported.
+
+Additional commands:
+    print cfgs    Print the `arduino_arch` and `arduino_mcu` cfgs for the board
+    print target  Print the target triple, the name of the board's target
+                  specification and the CPU that rustc compiles for
+    menus         List the board's menu options, such as the processor or
+                  clock speed, marking the selected ones with `*`
+    libraries     Print the name and path of each library that can be linked,
+                  from the board's platform and the configured directories
+    versions      Print the versions of the tools carguino runs, including the
+                  board's compilers if a target-board is configured
+    doctor        Check the Arduino installation, the board and the Rust
+                  toolchain, with hints on how to fix any problems
+    cache info    Print the size of each of carguino's caches
+    cache clear   Remove the caches selected by `--prefs` (the boards' build
+                  settings), `--targets` (target specifications), `--includes`
+                  (the compilers' system include directories) or `--all`
+    watch         Build, then rebuild whenever a file in the project changes,
+                  taking the arguments of `build`; the board's build settings
+                  are only retrieved once, so restart it after changing the
+                  configuration. The post-build command only runs after each
+                  build if `--serial-port` is given
+
+Post-build command:
+    The command given by `--post-build` or the `post-build-command` setting in
+    the `[arduino-builder]` section of the configuration is split into
+    arguments first, using single or double quotes to group arguments that
+    contain spaces. Afterwards these placeholders are substituted in each
+    argument, so paths containing spaces never need extra quoting:
+    {elf}          Path of the built ELF file
+    {hex}          Path of the extracted Intel HEX file
+    {eep}          Path of the extracted EEPROM data; the command is skipped
+                   for binaries without EEPROM data
+    {artifact}     Path of the ELF file without its extension
+    {port}         Serial port given by `--serial-port`
+
+Feature defines:
+    The `[feature-defines]` section of the configuration maps cargo features to
+    defines for the C/C++ code, e.g. `logging = "ENABLE_LOGGING"`. The define
+    of each feature that is enabled for the build (by default, `--features` or
+    `--all-features`) is passed to the compilers of the core and libraries.
+
+Default arguments:
+    The `args` in the `[defaults]` section of the configuration are passed on
+    to cargo before the command line's own arguments by `build`, `check`,
+    `clippy`, `doc`, `rustc` and `rustdoc`, e.g. `args = ["--release"]`. An
+    option given on the command line replaces the default for that option.
+
+RUSTFLAGS:
+    carguino passes its flags to cargo in $RUSTFLAGS, which makes cargo ignore
+    the `rustflags` in its configuration files (`.cargo/config`), so carguino
+    reads them itself: from the `[target.<target>]` sections for the board's
+    target specification or triple and the `[target.'cfg(...)']` sections
+    matching `target_arch`, `target_os`, `arduino_arch` or `arduino_mcu`, or
+    otherwise from `[build]`. Flags in $RUSTFLAGS replace them, as with cargo.
+    Both come after the architecture's default flags, so they take precedence:
+    avr    -C opt-level=s
+
+Environment:
+    CARGUINO_CACHE_DIR     Directory for cached build settings and target
+                           specifications (default: `$HOME/.carguino`)
+    NO_COLOR               Print carguino's messages without colors, unless
+                           `--color` is given
+
+Exit status:
+    0    The command completed successfully
+    1    Compiling or linking failed
+    2    A required tool could not be run or the configuration is invalid
+    3    Invalid command-line arguments were given
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     arg_command: String,
-    arg_args: Vec<String>,
-    flag_target_board: String,
-    flag_serial_port: String
+    arg_args: Vec<String>
 }
 
 fn main() {
     let mut config = Config::default();
 
     if let Err(error) = run(&mut config) {
-        config.shell().error(error).unwrap();
-        process::exit(1);
+        let code = match *error.kind() {
+            ErrorKind::Compilation(_) => 1,
+            ErrorKind::Docopt(_) | ErrorKind::InvalidArgument(_) => 3,
+            _ => 2
+        };
+        config.error(error).unwrap();
+        process::exit(code);
     }
 }
 
@@ -110,53 +257,173 @@ fn run(config: &mut Config) -> Result<()> {
         args => args
     }?;
 
-    let cargo_args = config.parse_options(arg_args)?;
+    // docopt only separates the command; the options given before it are handled like the ones after it.
+    let argv = env::args().skip(1).collect::<Vec<_>>();
+    let leading_options = argv[..argv.len() - arg_args.len() - 1].to_vec();
+    let cargo_args = config.parse_options(leading_options.into_iter().chain(arg_args).collect())?;
     let current_dir = env::current_dir().chain_err(|| "Unable to access current directory")?;
     config.parse_files(&current_dir)?;
 
-    cargo_run(&arg_command, &cargo_args, config)
+    if config.dump_config() {
+        print!("{}", config.effective_config()?);
+        return Ok(());
+    }
+
+    if let Some(path) = config.emit_spec().map(Path::to_path_buf) {
+        return emit_spec_run(&path, config);
+    }
+
+    if arg_command == "print" {
+        print_run(&cargo_args, config)
+    } else if arg_command == "menus" {
+        menus_run(config)
+    } else if arg_command == "libraries" {
+        libraries_run(config)
+    } else if arg_command == "versions" {
+        versions_run(config)
+    } else if arg_command == "doctor" {
+        doctor_run(config)
+    } else if arg_command == "cache" {
+        cache_run(&cargo_args, config)
+    } else if arg_command == "watch" {
+        let cargo_args = config.with_default_args("build", cargo_args);
+        watch_run(&cargo_args, config)
+    } else {
+        let cargo_args = config.with_default_args(&arg_command, cargo_args);
+        cargo_run(&arg_command, &cargo_args, config)
+    }
 }
 
 fn cargo_run(command: &str, args: &[String], config: &mut Config) -> Result<()> {
-    let builder = if let Some(builder) = config.create_builder() {
+    let builder = if let Some(builder) = config.create_builder()? {
         builder
     } else {
-        config.shell().warn("No target-board was specified; running cargo normally.")?;
+        config.warn("No target-board was specified; running cargo normally.")?;
         let mut cargo = util::process("cargo");
         config.add_message_format_option(&mut cargo);
-        cargo.arg(command).args(args).exec()?;
+        cargo.arg(command);
+        config.add_config_options(&mut cargo);
+        config.explain("Passing the command on to cargo, as there is no board to build for")?;
+        cargo.args(args).exec().map_err(error::compilation_error)?;
         return Ok(());
     };
 
-    config.shell().verbose(|shell| {
-        shell.status_ext("Retrieving", format_args!("build settings"))
+    let mut timings = Timings::new();
+    let build = prepare_build(&builder, command, args, config, &mut timings).map_err(|error| {
+        explain_build_failure(error, config)
     })?;
+    run_build(&build, args, config, &mut timings).map_err(|error| explain_build_failure(error, config))?;
 
-    let prefs = {
-        let temp_dir = TempDir::new("carguino").chain_err(|| "Could not create temporary directory")?;
-        let temp_file = temp_dir.path().join("project.c");
-        File::create(&temp_file).chain_err(|| "Could not create temporary project file")?;
+    if let Some(ref recipe_timings_file) = build.recipe_timings_file {
+        let report_file = build.timings_dir.join("carguino-timing.json");
+        timings.write_report(recipe_timings_file, &report_file)?;
+        config.status("Timing", format_args!("report saved to {}", report_file.display()))?;
+    }
 
-        builder.dump_prefs(&temp_file)?
-    };
+    Ok(())
+}
 
-    let board_name = prefs.get::<String>("name")
-                               .map_or_else(|| Err("'name' missing from preferences"), Ok)?;
+fn watch_run(args: &[String], config: &mut Config) -> Result<()> {
+    let builder = config.create_builder()?.map_or_else(|| Err("`watch` requires a target-board"), Ok)?;
 
-    config.shell().status_ext("Configuring", board_name)?;
+    let mut timings = Timings::new();
+    let mut build = prepare_build(&builder, "build", args, config, &mut timings).map_err(|error| {
+        explain_build_failure(error, config)
+    })?;
+    // Uploading after every change is opt-in, as the board may not be connected while editing.
+    build.upload = config.serial_port().is_some();
+    if !build.upload && config.post_build_command().is_some() {
+        config.status("Skipping", "post-build command; give `--serial-port` to run it after each build")?;
+    }
 
-    let target_mcu = prefs.get::<String>("build.mcu")
-                               .map_or_else(|| Err("'build.mcu' missing from preferences"), Ok)?;
-    let target_arch = prefs.get::<String>("build.arch")
-                                .map(|s| s.to_lowercase())
-                                .map_or_else(|| Err("'build.arch' missing from preferences"), Ok)?;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::watcher(sender, Duration::from_millis(WATCH_DEBOUNCE_MS))
+                             .chain_err(|| "Could not watch the project for changes")?;
+    watcher.watch(&build.project_dir, RecursiveMode::Recursive)
+           .chain_err(|| format!("Could not watch {}", build.project_dir.display()))?;
+
+    // The toolchain does not change while watching, so it only has to be checked after the first failure.
+    let mut toolchain_checked = false;
+    loop {
+        // A failed build is reported, but the next change is still picked up.
+        if let Err(error) = run_build(&build, args, config, &mut Timings::new()) {
+            let error = if toolchain_checked { error } else { explain_build_failure(error, config) };
+            toolchain_checked = true;
+            config.error(error)?;
+        }
+        config.status("Watching", format_args!("{} for changes", build.project_dir.display()))?;
+
+        loop {
+            let event = receiver.recv().chain_err(|| "Stopped watching the project for changes")?;
+            if let Some(path) = changed_path(event) {
+                if !ignore_change(&path, &build) {
+                    config.verbose_status("Changed", path.display())?;
+                    break;
+                }
+            }
+        }
+    }
+}
 
-    let linker_recipe = prefs.get::<String>("recipe.c.combine.pattern")
-                                  .map_or_else(|| Err("'recipe.c.combine.pattern' missing from preferences"), Ok)?;
+fn changed_path(event: DebouncedEvent) -> Option<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) |
+        DebouncedEvent::Rename(_, path) => Some(path),
+        _ => None
+    }
+}
 
-    let platform_dir = prefs.get::<String>("runtime.platform.path")
-                                 .map(PathBuf::from)
-                                 .map_or_else(|| Err("'runtime.platform.path' missing from preferences"), Ok)?;
+// The build's own output and hidden files, such as editor swap files and `.git`, do not cause a rebuild.
+fn ignore_change(path: &Path, build: &Build) -> bool {
+    path.starts_with(&build.target_dir) || path.strip_prefix(&build.project_dir).map_or(false, |path| {
+        path.components().any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+    })
+}
+
+// Everything up to the cargo build only depends on the board and the configuration, so `watch` does it once.
+struct Build {
+    xargo_base: ProcessBuilder,
+    link: bool,
+    package_id: String,
+    project_dir: PathBuf,
+    target_dir: PathBuf,
+    objcopy_recipes: Vec<(String, String, Vec<String>)>,
+    linker_command: String,
+    uf2_family: Option<u32>,
+    usb_ids: Vec<(u16, u16)>,
+    use_1200bps_touch: bool,
+    wait_for_upload_port: bool,
+    upload: bool,
+    build_info: Option<BuildInfo>,
+    recipe_timings_file: Option<PathBuf>,
+    timings_dir: PathBuf
+}
+
+// What a binary was built from, written next to it with `--build-info`.
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    board: String,
+    profile: &'static str,
+    carguino: &'static str,
+    rustc: Option<String>,
+    rustflags: String,
+    #[serde(rename = "git-commit")]
+    git_commit: Option<String>
+}
+
+fn prepare_build(builder: &Builder, command: &str, args: &[String], config: &mut Config,
+                 timings: &mut Timings) -> Result<Build> {
+    let start = Instant::now();
+    let mut prefs = retrieve_prefs(builder, config)?;
+    timings.record("dump preferences", start);
+    check_menu_selection(config.target_board().unwrap(), &prefs)?;
+
+    let board_name = prefs.get::<String>("name")
+                               .map_or_else(|| Err("'name' missing from preferences"), Ok)?;
+
+    config.status("Configuring", board_name)?;
+
+    let (target_arch, target_mcu) = target_arch_mcu(&prefs)?;
 
     let objcopy_regex = Regex::new(r#"^recipe\.objcopy\.(\w+)\.pattern"#).unwrap();
     let objcopy_recipes = prefs.keys().filter_map(|key| {
@@ -168,10 +435,8 @@ fn cargo_run(command: &str, args: &[String], config: &mut Config) -> Result<()>
         })
     }).collect::<Vec<_>>();
 
-    let mut library_paths = HashMap::new();
-    detect_libraries(&platform_dir.join("libraries"), &mut library_paths, config.shell())?;
-
-    let linker_options = parse_linker_options(&linker_recipe);
+    let library_paths = available_libraries(&prefs, config)?;
+    let linker_options = board_linker_options(&prefs, config)?;
 
     let base_flags = &[
         format!(r#"--cfg arduino_arch="{}""#, target_arch),
@@ -181,63 +446,305 @@ fn cargo_run(command: &str, args: &[String], config: &mut Config) -> Result<()>
     let mut rustdocflags = Vec::from_iter(env::var("RUSTDOCFLAGS"));
     rustdocflags.extend_from_slice(base_flags);
 
-    let mut rustflags = Vec::from_iter(env::var("RUSTFLAGS"));
-    rustflags.extend_from_slice(base_flags);
-
     let mut cargo_metadata = util::process("cargo");
     cargo_metadata.arg("metadata").arg("--no-deps");
+    config.add_config_options(&mut cargo_metadata);
 
-    config.shell().verbose(|shell| {
-        shell.status_ext("Running", &cargo_metadata)
-    })?;
+    config.explain("Asking cargo for the package ID and target directory")?;
+    config.verbose_status("Running", &cargo_metadata)?;
 
+    let start = Instant::now();
     let output = cargo_metadata.exec_with_output()?;
     let metadata = serde_json::from_slice::<Value>(&output.stdout).unwrap();
-    let package_id = metadata["packages"][0]["id"].as_str().unwrap().to_string();
-    let targets_dir = env::home_dir().unwrap().join(".carguino/targets");
+    let manifest_dir = match config.manifest_path().and_then(Path::parent) {
+        Some(dir) => fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()),
+        None => env::current_dir().chain_err(|| "Unable to access current directory")?
+    };
+    let package = project_package(&metadata, &manifest_dir).map_or_else(|| {
+        Err(format!("Could not find the package of {} in cargo's metadata", manifest_dir.display()))
+    }, Ok)?;
+    let package_id = package["id"].as_str().unwrap().to_string();
+    let project_dir = Path::new(package["manifest_path"].as_str().unwrap()).parent().unwrap().to_path_buf();
+    let target_dir = target_dir(&metadata, &project_dir);
+    timings.record("cargo metadata", start);
+
+    let features = active_features(&package["features"], args);
+    let mut feature_defines = Vec::new();
+    for (feature, define) in config.feature_defines() {
+        if features.contains(&feature) {
+            config.verbose_status("Using", format_args!("define {} for feature `{}`", define, feature))?;
+            feature_defines.push(format!("-D{}", define));
+        }
+    }
+
+    let start = Instant::now();
+    let targets_dir = cache::cache_dir().join("targets");
     fs::create_dir_all(&targets_dir).chain_err(|| "Could not create targets directory")?;
     let (llvm_target, target) = create_target_spec(config, &linker_options, &targets_dir, &target_arch, &target_mcu)?;
+    timings.record("target specification", start);
+
+    // Later flags override earlier ones, so the user's RUSTFLAGS come after the defaults.
+    let mut rustflags = Vec::new();
+    if config.default_rustflags() {
+        rustflags.extend(default_rustflags(&target_arch).into_iter().map(String::from));
+    }
+    match env::var("RUSTFLAGS") {
+        Ok(flags) => rustflags.push(flags),
+        Err(_) => {
+            let cfgs = target_cfgs(llvm_target, &target_arch, &target_mcu);
+            rustflags.extend(cargo_config::rustflags(&project_dir, &[&target, llvm_target], &cfgs)?);
+        }
+    }
+    rustflags.extend_from_slice(base_flags);
+    if let Some(level) = config.opt_level() {
+        rustflags.push(format!("-C opt-level={}", level));
+    }
+
+    let timings_dir = target_dir.join("carguino-timings");
+    let recipe_timings_file = if config.timings() {
+        fs::create_dir_all(&timings_dir).chain_err(|| "Could not create timings directory")?;
+        let file = timings_dir.join("recipes.jsonl");
+        if file.exists() {
+            fs::remove_file(&file).chain_err(|| "Could not remove previous recipe timings")?;
+        }
+        Some(file)
+    } else {
+        None
+    };
+
+    // Platforms that support debugging define optimization flags for both profiles (as used by Arduino IDE 2).
+    let profile = if is_release(args) { "release" } else { "debug" };
+    if let Some(flags) = prefs.get_unexpanded::<String>(&format!("compiler.optimization_flags.{}", profile)) {
+        config.verbose_status("Using", format_args!("{} optimization flags for C/C++ sources: {}", profile, flags))?;
+        prefs.set("compiler.optimization_flags", flags);
+    }
 
+    let usb_ids = serial::usb_ids(&prefs);
+    let use_1200bps_touch = prefs.get::<String>("upload.use_1200bps_touch").map_or(false, |value| value == "true");
+    let wait_for_upload_port = prefs.get::<String>("upload.wait_for_upload_port").map_or(false, |value| value == "true");
+
+    // An explicit level replaces the optimization option of the compile recipes, so C, C++ and Rust code match.
+    if let Some(level) = config.opt_level() {
+        let flag = c_optimization_flag(level);
+        for key in &["recipe.c.o.pattern", "recipe.cpp.o.pattern"] {
+            if let Some(recipe) = prefs.get::<String>(key) {
+                prefs.set(key, replace_optimization_flag(&recipe, flag));
+            }
+        }
+        config.verbose_status("Using", format_args!("optimization level {} ({} for C/C++ sources)", level, flag))?;
+    }
+
+    let uf2_family = match config.uf2_family() {
+        Some(family_id) => Some(family_id),
+        None => prefs.get::<String>("build.uf2_family").and_then(|name| uf2::family_id(&name))
+                     .or_else(|| uf2::board_family_id(&target_arch, &target_mcu))
+    };
+
+    let build_info = if config.build_info() {
+        let mut git = util::process("git");
+        git.arg("rev-parse").arg("HEAD").cwd(&project_dir);
+        Some(BuildInfo {
+            board: config.target_board().unwrap().to_string(),
+            profile: profile,
+            carguino: VERSION_STRING,
+            rustc: rustc_version(),
+            rustflags: rustflags.join(" "),
+            git_commit: tool_version(&git)
+        })
+    } else {
+        None
+    };
+
+    let link = links_firmware(command);
+    // The core's build script watches this file, so a new request rebuilds the core once without changing the
+    // configuration of every other build script.
+    let rebuild_core_file = target_dir.join("carguino-rebuild-core");
+    if link && config.rebuild_core() {
+        config.status("Rebuilding", "the board's core")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        write_rebuild_request(&rebuild_core_file, &format!("{}.{:09}", now.as_secs(), now.subsec_nanos()))?;
+    } else if !rebuild_core_file.exists() {
+        write_rebuild_request(&rebuild_core_file, "")?;
+    }
     let mut xargo_base = util::process("xargo");
-    xargo_base.env("CARGUINO_CONFIG", build_config::Config::serialize(prefs, llvm_target, &target_arch, library_paths)?)
+    let options = build_config::Options {
+        board_id: config.target_board().unwrap().to_string(),
+        archiver_override: config.archiver_override().map(PathBuf::from),
+        recipe_overrides: config.recipe_overrides(),
+        variant_includes_first: config.variant_includes_first(),
+        includes_cache_dir: Some(cache::cache_dir().join("includes")),
+        include_dirs: config.include_dirs().to_vec(),
+        defines: feature_defines,
+        verbose: config.is_verbose(),
+        quiet: config.is_quiet(),
+        link: link,
+        dump_objects: config.dump_objects(),
+        rebuild_core_file: Some(rebuild_core_file),
+        timings_file: recipe_timings_file.clone()
+    };
+    xargo_base.env("CARGUINO_FLAGS", build_config::Config::serialize_flags(&options)?)
+              .env("CARGUINO_CONFIG", build_config::Config::serialize(prefs, llvm_target, &target_arch, library_paths,
+                                                                      options)?)
               .env("RUSTFLAGS", rustflags.join(" "))
               .env("RUSTDOCFLAGS", rustdocflags.join(" "))
               .env("RUST_TARGET_PATH", targets_dir)
               .arg(command)
               .arg("--target").arg(target);
+    config.add_config_options(&mut xargo_base);
+    // xargo looks for `Xargo.toml` next to the manifest in the directory it is run from.
+    if let Some(project_dir) = config.manifest_path().and_then(Path::parent) {
+        xargo_base.cwd(project_dir);
+    }
 
-    let mut xargo_pass1 = xargo_base.clone();
-    config.add_message_format_option(&mut xargo_pass1);
+    Ok(Build {
+        xargo_base: xargo_base,
+        link: link,
+        package_id: package_id,
+        project_dir: project_dir,
+        target_dir: target_dir,
+        objcopy_recipes: objcopy_recipes,
+        linker_command: linker_options.command,
+        uf2_family: uf2_family,
+        usb_ids: usb_ids,
+        use_1200bps_touch: use_1200bps_touch,
+        wait_for_upload_port: wait_for_upload_port,
+        upload: true,
+        build_info: build_info,
+        recipe_timings_file: recipe_timings_file,
+        timings_dir: timings_dir
+    })
+}
+
+// Like cargo, as many jobs run at once as given by `-j`, or as there are CPUs.
+fn jobs(args: &[String]) -> usize {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = if arg == "-j" || arg == "--jobs" {
+            iter.next().map(String::as_str)
+        } else if arg.starts_with("--jobs=") {
+            Some(&arg["--jobs=".len()..])
+        } else if arg.starts_with("-j") {
+            Some(&arg[2..])
+        } else {
+            None
+        };
+        if let Some(jobs) = value.and_then(|value| value.parse::<usize>().ok()) {
+            return cmp::max(jobs, 1);
+        }
+    }
+    num_cpus::get()
+}
+
+// Runs the commands on a pool of at most `jobs` threads. The output is captured, so that concurrent runs do not
+// interleave, and failures are returned in the order of the commands.
+fn run_parallel(commands: Vec<ProcessBuilder>, jobs: usize) -> Vec<String> {
+    let count = commands.len();
+    let queue = Arc::new(Mutex::new(commands.into_iter().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..cmp::min(jobs, count) {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((index, command)) => {
+                        let result = command.exec_with_output().map(|_| ()).map_err(|error| error.to_string());
+                        sender.send((index, result)).unwrap();
+                    }
+                    None => break
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut results = vec![None; count];
+    for (index, result) in receiver {
+        results[index] = Some(result);
+    }
+    results.into_iter().filter_map(|result| {
+        match result {
+            Some(Ok(())) => None,
+            Some(Err(error)) => Some(error),
+            None => Some("objcopy thread panicked".to_string())
+        }
+    }).collect()
+}
+
+fn write_rebuild_request(file: &Path, request: &str) -> Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).chain_err(|| "Could not create target directory")?;
+    }
+    File::create(file).and_then(|mut file| file.write_all(request.as_bytes())).chain_err(|| {
+        "Could not write the request to rebuild the core"
+    })
+}
+
+fn run_build(build: &Build, args: &[String], config: &mut Config, timings: &mut Timings) -> Result<()> {
+    // Unless asked for two passes, a build always runs with JSON messages, from which the artifacts are collected and
+    // rustc's diagnostics are printed as usual when the user did not ask for JSON.
+    let single_pass = build.link && (config.json_message_format() || !config.two_pass());
+    let mut xargo_pass1 = build.xargo_base.clone();
+    if single_pass {
+        xargo_pass1.arg("--message-format").arg("json");
+    } else {
+        config.add_message_format_option(&mut xargo_pass1);
+    }
     xargo_pass1.args(args);
-    config.shell().verbose(|shell| {
-        shell.status_ext("Running", &xargo_pass1)
-    })?;
-    xargo_pass1.exec()?;
+    config.explain("Building the sysroot and the package for the board with xargo")?;
+    config.verbose_status("Running", &xargo_pass1)?;
+    let start = Instant::now();
+    let json_output = if single_pass && config.json_message_format() {
+        Some(exec_capturing_stdout(&xargo_pass1, forward_line)?)
+    } else if single_pass {
+        Some(exec_capturing_stdout(&xargo_pass1, render_message)?)
+    } else {
+        xargo_pass1.exec().map_err(error::compilation_error)?;
+        None
+    };
+    timings.record("build", start);
 
-    let mut xargo_pass2 = xargo_base;
-    xargo_pass2.arg("--message-format").arg("json")
-               .args(args);
+    let artifacts = if !build.link {
+        Vec::new()
+    } else {
+        let stdout = match json_output {
+            Some(stdout) => stdout,
+            None => {
+                let mut xargo_pass2 = build.xargo_base.clone();
+                xargo_pass2.arg("--message-format").arg("json")
+                           .args(args);
+
+                config.explain("Repeating the (now fresh) build with JSON messages to find the firmware ELF files")?;
+                let start = Instant::now();
+                let output = xargo_pass2.exec_with_output().map_err(error::compilation_error)?;
+                timings.record("collect artifacts", start);
+                output.stdout
+            }
+        };
 
-    let output = xargo_pass2.exec_with_output()?;
+        let (artifacts, other_targets) = collect_artifacts(&stdout, &build.package_id);
+        if !other_targets.is_empty() {
+            config.status("Skipping", format_args!("post-processing of {}", other_targets.join(", ")))?;
+        }
+        // Otherwise a build without any firmware would end as if it had done everything.
+        if artifacts.is_empty() {
+            let reason = if other_targets.is_empty() {
+                "the package has no binary target, or the command's options left it out"
+            } else {
+                "none of the built targets is a binary"
+            };
+            config.status("Skipping", format_args!("extracting the firmware and the post-build command; {}", reason))?;
+        }
 
-    let stdout = BufReader::new(Cursor::new(output.stdout));
-    let artifacts = stdout.lines().filter_map(|line| {
-        line.ok().and_then(|line| {
-            serde_json::from_str::<Value>(&line).ok()
-        })
-    }).filter(|message| {
-        message["reason"].as_str() == Some("compiler-artifact")
-        && message["package_id"].as_str() == Some(package_id.as_str())
-        && message["target"]["kind"].as_array().unwrap().iter().any(|kind| kind.as_str() == Some("bin"))
-    }).flat_map(|message| {
-        message["filenames"].as_array().unwrap().clone()
-    }).map(|artifact| {
-        PathBuf::from(artifact.as_str().unwrap())
-    }).collect::<Vec<_>>();
+        artifacts
+    };
 
     if !artifacts.is_empty() {
-        for &(ref extension, ref command, ref options) in &objcopy_recipes {
-            config.shell().status_ext("Extracting", format_args!("{} data for {}", extension, package_id))?;
+        let start = Instant::now();
+        let mut commands = Vec::new();
+        for &(ref extension, ref command, ref options) in &build.objcopy_recipes {
+            config.status("Extracting", format_args!("{} data for {}", extension, build.package_id))?;
 
             for artifact in &artifacts {
                 let mut objcopy = util::process(command);
@@ -245,19 +752,756 @@ fn cargo_run(command: &str, args: &[String], config: &mut Config) -> Result<()>
                        .arg(artifact)
                        .arg(artifact.with_extension(extension));
 
-                config.shell().verbose(|shell| {
-                    shell.status_ext("Running", &objcopy)
+                config.explain(format_args!("Converting the firmware ELF to the `{}` format for uploading", extension))?;
+                config.verbose_status("Running", &objcopy)?;
+                commands.push(objcopy);
+            }
+        }
+        let failures = run_parallel(commands, jobs(args));
+        timings.record("objcopy", start);
+
+        if !failures.is_empty() {
+            bail!("Could not extract data from {}:\n{}", build.package_id, failures.join("\n"));
+        }
+
+        if let Some(ref build_info) = build.build_info {
+            for artifact in &artifacts {
+                let info_file = artifact.with_extension("buildinfo.json");
+                let mut file = File::create(&info_file).chain_err(|| format!("Could not create {}", info_file.display()))?;
+                serde_json::to_writer_pretty(&mut file, build_info).chain_err(|| {
+                    format!("Could not write {}", info_file.display())
                 })?;
+                config.verbose_status("Wrote", format_args!("build information to {}", info_file.display()))?;
+            }
+        }
+
+        if config.strip() {
+            let strip_tool = binutils_command(&build.linker_command, "strip");
+            for artifact in &artifacts {
+                let stripped = artifact.with_extension("stripped.elf");
+                let mut strip = util::process(&strip_tool);
+                strip.arg("--strip-all").arg("-o").arg(&stripped).arg(artifact);
+
+                config.explain("Removing the symbols from a copy of the firmware ELF for distribution")?;
+                config.verbose_status("Running", &strip)?;
+                strip.exec()?;
+
+                let size = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+                config.status("Stripped", format_args!("{} ({} bytes, {} bytes unstripped)", stripped.display(),
+                                                       size(&stripped), size(artifact)))?;
+            }
+        }
+
+        if let Some(flags) = config.disassemble().map(String::from) {
+            let objdump_tool = binutils_command(&build.linker_command, "objdump");
+            for artifact in &artifacts {
+                let listing = artifact.with_extension("lst");
+                let mut objdump = util::process(&objdump_tool);
+                objdump.arg("-d").arg("-S").args(&flags.split_whitespace().collect::<Vec<_>>()).arg(artifact);
+
+                config.explain("Disassembling the firmware ELF, interleaved with its source where debug info allows")?;
+                config.verbose_status("Running", &objdump)?;
+                let output = objdump.exec_with_output()?;
+                File::create(&listing).and_then(|mut file| file.write_all(&output.stdout))
+                                      .chain_err(|| format!("Could not write {}", listing.display()))?;
+
+                config.status("Disassembled", listing.display())?;
+            }
+        }
+
+        if config.uf2() {
+            for artifact in &artifacts {
+                let uf2_file = artifact.with_extension("uf2");
+                let mut elf = Vec::new();
+                File::open(artifact).and_then(|mut file| file.read_to_end(&mut elf))
+                                    .chain_err(|| format!("Could not read {}", artifact.display()))?;
+                let uf2 = uf2::convert(&elf, build.uf2_family).chain_err(|| format!("Could not convert {} to UF2",
+                                                                              artifact.display()))?;
+                File::create(&uf2_file).and_then(|mut file| file.write_all(&uf2))
+                                       .chain_err(|| format!("Could not write {}", uf2_file.display()))?;
+
+                config.status("Converted", format_args!("{} ({} blocks{})", uf2_file.display(), uf2.len() / 512,
+                                                        build.uf2_family.map_or_else(String::new, |family_id| {
+                                                            format!(", family 0x{:08x}", family_id)
+                                                        })))?;
+            }
+        }
+
+        let post_build_command = if build.upload { config.post_build_command().map(String::from) } else { None };
+        if let Some(command_line) = post_build_command {
+            let port = if command_line.contains("{port}") {
+                let port = serial_port(config, &build.usb_ids)?;
+                if build.use_1200bps_touch {
+                    Some(reset_board(config, port, build.wait_for_upload_port)?)
+                } else {
+                    Some(port)
+                }
+            } else {
+                None
+            };
+            for artifact in &artifacts {
+                // Not every sketch has EEPROM contents, in which case objcopy does not produce an `.eep` file.
+                if command_line.contains("{eep}") && !artifact.with_extension("eep").is_file() {
+                    config.status("Skipping", format_args!("post-build command for {}; no EEPROM data was produced",
+                                                           artifact.display()))?;
+                    continue;
+                }
+
+                let mut post_build = post_build_command(&command_line, artifact, port.as_ref().map(String::as_str));
+
+                config.explain("Running the post-build command for the firmware")?;
+                config.verbose_status("Running", &post_build)?;
+
+                let attempts = config.upload_retries() + 1;
+                for attempt in 1..attempts + 1 {
+                    match post_build.exec() {
+                        Ok(()) => break,
+                        Err(ref error) if attempt < attempts => {
+                            config.status("Retrying", format_args!("post-build command (attempt {} of {} failed: {})",
+                                                                   attempt, attempts, error))?;
+                        }
+                        Err(error) => return Err(error.into())
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Each line is passed on as soon as it is received, so tools reading the messages see the progress of the build.
+// The output is kept to collect the artifacts from, while `forward` shows each line as it arrives.
+fn exec_capturing_stdout<F: FnMut(&[u8]) -> Result<()>>(process: &ProcessBuilder, mut forward: F) -> Result<Vec<u8>> {
+    let mut child = process.build_command().stdout(Stdio::piped()).spawn()
+                           .chain_err(|| format!("Could not run {}", process))?;
+
+    let mut captured = Vec::new();
+    for line in BufReader::new(child.stdout.take().unwrap()).split(b'\n') {
+        let mut line = line.chain_err(|| format!("Could not read the output of {}", process))?;
+        line.push(b'\n');
+        forward(&line)?;
+        captured.extend(line);
+    }
+
+    let status = child.wait().chain_err(|| format!("Could not run {}", process))?;
+    if !status.success() {
+        let error = util::process_error(&format!("process didn't exit successfully: {}", process), None,
+                                        Some(&status), None);
+        return Err(error::compilation_error(error));
+    }
+    Ok(captured)
+}
+
+fn forward_line(line: &[u8]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(line).and_then(|_| stdout.flush()).chain_err(|| "Could not write to stdout")
+}
+
+// Prints rustc's diagnostics from JSON messages as they would have been printed with human-readable messages; other
+// messages only report artifacts, and anything else is passed on.
+fn render_message(line: &[u8]) -> Result<()> {
+    let message = match serde_json::from_slice::<Value>(line) {
+        Ok(message) => message,
+        Err(_) => return forward_line(line)
+    };
+    if message["reason"].as_str() != Some("compiler-message") {
+        return Ok(());
+    }
+
+    let diagnostic = &message["message"];
+    let rendered = diagnostic["rendered"].as_str().map_or_else(|| {
+        format!("{}: {}\n", diagnostic["level"].as_str().unwrap_or("error"), diagnostic["message"].as_str().unwrap_or(""))
+    }, String::from);
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    stderr.write_all(rendered.as_bytes()).and_then(|_| stderr.flush()).chain_err(|| "Could not write to stderr")
+}
+
+// Only binaries are post-processed; other targets except libraries (e.g. tests and benches built with
+// `--all-targets`) are returned by name and kind so they can be reported.
+fn collect_artifacts(stdout: &[u8], package_id: &str) -> (Vec<PathBuf>, Vec<String>) {
+    let mut binaries = Vec::new();
+    let mut other_targets = Vec::new();
+
+    let messages = BufReader::new(Cursor::new(stdout)).lines().filter_map(|line| {
+        line.ok().and_then(|line| {
+            serde_json::from_str::<Value>(&line).ok()
+        })
+    }).filter(|message| {
+        message["reason"].as_str() == Some("compiler-artifact")
+        && message["package_id"].as_str() == Some(package_id)
+    });
+
+    for message in messages {
+        let kinds = message["target"]["kind"].as_array().cloned().unwrap_or_default();
+        let is_test = message["profile"]["test"].as_bool() == Some(true);
+        if !is_test && kinds.iter().any(|kind| kind.as_str() == Some("bin")) {
+            binaries.extend(message["filenames"].as_array().unwrap().iter().map(|artifact| {
+                PathBuf::from(artifact.as_str().unwrap())
+            }));
+        } else if is_test || !kinds.iter().any(|kind| kind.as_str().map_or(false, |kind| kind.ends_with("lib"))) {
+            let kind = if is_test { "test" } else { kinds.get(0).and_then(Value::as_str).unwrap_or("unknown") };
+            other_targets.push(format!("{} ({})", message["target"]["name"].as_str().unwrap_or("?"), kind));
+        }
+    }
+
+    (binaries, other_targets)
+}
+
+// Older versions of GCC, as shipped with most platforms, do not support `-Oz`.
+fn c_optimization_flag(level: &str) -> &'static str {
+    match level {
+        "0" => "-O0",
+        "1" => "-O1",
+        "2" => "-O2",
+        "3" => "-O3",
+        _ => "-Os"
+    }
+}
+
+fn replace_optimization_flag(recipe: &str, flag: &str) -> String {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r#"(^|\s)-O[0-3sgz]?(\s|$)"#).unwrap();
+    }
+    if REGEX.is_match(recipe) {
+        REGEX.replace_all(recipe, |captures: &Captures| format!("{}{}{}", &captures[1], flag, &captures[2])).into_owned()
+    } else {
+        format!("{} {}", recipe, flag)
+    }
+}
+
+// Binutils have the same prefix as the linker (e.g. `avr-gcc` and `avr-strip`).
+fn binutils_command(linker: &str, tool: &str) -> PathBuf {
+    let linker = Path::new(linker);
+    let file_name = linker.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    let command = match file_name.rfind('-') {
+        Some(index) => format!("{}-{}{}", &file_name[..index], tool, env::consts::EXE_SUFFIX),
+        None => format!("{}{}", tool, env::consts::EXE_SUFFIX)
+    };
+    linker.with_file_name(command)
+}
+
+fn post_build_command(command_line: &str, artifact: &Path, port: Option<&str>) -> ProcessBuilder {
+    let substitute = |arg: &str| {
+        arg.replace("{elf}", &artifact.to_string_lossy())
+           .replace("{hex}", &artifact.with_extension("hex").to_string_lossy())
+           .replace("{eep}", &artifact.with_extension("eep").to_string_lossy())
+           .replace("{artifact}", &artifact.with_extension("").to_string_lossy())
+           .replace("{port}", port.unwrap_or(""))
+    };
+
+    let (program, args) = build_config::split_command_line(command_line);
+    let mut command = util::process(substitute(&program.to_string_lossy()));
+    command.args(&args.iter().map(|arg| substitute(arg)).collect::<Vec<_>>());
+    command
+}
+
+fn serial_port(config: &mut Config, usb_ids: &[(u16, u16)]) -> Result<String> {
+    match config.serial_port().map(String::from) {
+        Some(ref port) if port == "auto" => {
+            let port = serial::detect_port(usb_ids)?;
+            config.status("Detected", format_args!("board on {}", port))?;
+            Ok(port)
+        }
+        Some(port) => Ok(port),
+        None => bail!(ErrorKind::InvalidArgument("The post-build command uses {port}, but no serial port was given".to_string()))
+    }
+}
+
+fn reset_board(config: &mut Config, port: String, wait_for_upload_port: bool) -> Result<String> {
+    let previous_ports = serial::port_names();
+
+    config.status("Resetting", format_args!("board on {} into its bootloader", port))?;
+    config.explain("Opening the serial port at 1200 baud, which native USB boards take as a request to reset")?;
+    serial::touch(&port)?;
+
+    if wait_for_upload_port {
+        let upload_port = serial::wait_for_bootloader(&port, &previous_ports, config.upload_timeout())?;
+        config.verbose_status("Detected", format_args!("bootloader on {}", upload_port))?;
+        Ok(upload_port)
+    } else {
+        Ok(port)
+    }
+}
+
+fn print_run(args: &[String], config: &mut Config) -> Result<()> {
+    let request = match args.first().map(String::as_str) {
+        Some(request @ "cfgs") | Some(request @ "target") => request,
+        Some(request) => bail!(ErrorKind::InvalidArgument(format!("Unknown print request '{}' (expected `cfgs` or `target`)", request))),
+        None => bail!(ErrorKind::InvalidArgument("Expected `cfgs` or `target` after `print`".to_string()))
+    };
+
+    let builder = config.create_builder()?.map_or_else(|| {
+        Err(ErrorKind::InvalidArgument("`print` requires a target-board".to_string()))
+    }, Ok)?;
+    let prefs = retrieve_prefs(&builder, config)?;
+    check_menu_selection(config.target_board().unwrap(), &prefs)?;
+    let (target_arch, target_mcu) = target_arch_mcu(&prefs)?;
+
+    if request == "cfgs" {
+        println!(r#"arduino_arch="{}""#, target_arch);
+        println!(r#"arduino_mcu="{}""#, target_mcu);
+    } else {
+        let cpu = target_cpu(config, &target_arch, &target_mcu)?;
+        println!("triple={}", target_triple(&target_arch, &cpu)?);
+        println!("spec={}", spec_name(config.target_board().unwrap()));
+        println!("cpu={}", cpu);
+    }
+
+    Ok(())
+}
+
+fn emit_spec_run(path: &Path, config: &mut Config) -> Result<()> {
+    let builder = config.create_builder()?.map_or_else(|| {
+        Err(ErrorKind::InvalidArgument("`--emit-spec` requires a target-board".to_string()))
+    }, Ok)?;
+
+    // The path is checked before the slow steps of retrieving the preferences and generating the specification.
+    let mut file = File::create(path).chain_err(|| format!("Could not create {}", path.display()))?;
+    let spec = generate_spec(&builder, config).and_then(|(target, spec)| {
+        file.write_all(&spec).chain_err(|| format!("Could not write {}", path.display()))?;
+        Ok(target)
+    });
+    match spec {
+        Ok(target) => config.status("Emitted", format_args!("target specification {} to {}", target, path.display())),
+        Err(error) => {
+            let _ = fs::remove_file(path);
+            Err(error)
+        }
+    }
+}
+
+fn generate_spec(builder: &Builder, config: &mut Config) -> Result<(String, Vec<u8>)> {
+    let prefs = retrieve_prefs(builder, config)?;
+    check_menu_selection(config.target_board().unwrap(), &prefs)?;
+    let (target_arch, target_mcu) = target_arch_mcu(&prefs)?;
+    let linker_options = board_linker_options(&prefs, config)?;
+
+    let targets_dir = cache::cache_dir().join("targets");
+    fs::create_dir_all(&targets_dir).chain_err(|| "Could not create targets directory")?;
+    let (_, target) = create_target_spec(config, &linker_options, &targets_dir, &target_arch, &target_mcu)?;
+
+    let mut spec = Vec::new();
+    File::open(targets_dir.join(&target).with_extension("json")).and_then(|mut file| file.read_to_end(&mut spec))
+                                                               .chain_err(|| "Could not read target specification")?;
+    Ok((target, spec))
+}
+
+fn menus_run(config: &mut Config) -> Result<()> {
+    let builder = config.create_builder()?.map_or_else(|| {
+        Err(ErrorKind::InvalidArgument("`menus` requires a target-board".to_string()))
+    }, Ok)?;
+    let prefs = retrieve_prefs(&builder, config)?;
+    let board = config.target_board().unwrap();
+
+    let menus = board_menus(&prefs);
+    if menus.is_empty() {
+        println!("{} has no menu options", board);
+    }
+    for (menu, options) in &menus {
+        println!("{}", menu);
+        for &(ref option, ref label) in options {
+            let selected = board.params().get(menu) == Some(option);
+            println!("  {} {:<20} {}", if selected { "*" } else { " " }, option, label);
+        }
+    }
+
+    check_menu_selection(board, &prefs)
+}
+
+// Boards list their menu options as `menu.<menu>.<option>=<label>`, next to the settings each option changes.
+fn board_menus(prefs: &Preferences) -> BTreeMap<String, Vec<(String, String)>> {
+    let mut menus = BTreeMap::<String, Vec<(String, String)>>::new();
+    for key in prefs.keys() {
+        let parts = key.split('.').collect::<Vec<_>>();
+        if parts.len() == 3 && parts[0] == "menu" {
+            let label = prefs.get::<String>(key).unwrap_or_default();
+            menus.entry(parts[1].to_string()).or_insert_with(Vec::new).push((parts[2].to_string(), label));
+        }
+    }
+    menus
+}
 
-                objcopy.exec()?;
+fn check_menu_selection(board: &BoardInfo, prefs: &Preferences) -> Result<()> {
+    let menus = board_menus(prefs);
+    for (menu, value) in board.params() {
+        let options = match menus.get(menu) {
+            Some(options) => options,
+            None => {
+                let available = menus.keys().map(String::as_str).collect::<Vec<_>>();
+                bail!(ErrorKind::InvalidArgument(format!("Board has no menu '{}' (available menus: {})", menu,
+                                                         if available.is_empty() { "none".to_string() } else { available.join(", ") })))
             }
+        };
+        if !options.iter().any(|&(ref option, _)| option == value) {
+            bail!(ErrorKind::InvalidArgument(format!("Invalid option '{}' for menu '{}' (expected one of: {})", value, menu,
+                                                     options.iter().map(|&(ref option, _)| option.as_str())
+                                                            .collect::<Vec<_>>().join(", "))));
         }
     }
+    Ok(())
+}
 
+fn libraries_run(config: &mut Config) -> Result<()> {
+    let builder = config.create_builder()?.map_or_else(|| {
+        Err(ErrorKind::InvalidArgument("`libraries` requires a target-board".to_string()))
+    }, Ok)?;
+    let prefs = retrieve_prefs(&builder, config)?;
+
+    let libraries = available_libraries(&prefs, config)?.into_iter().collect::<BTreeMap<_, _>>();
+    for (name, path) in libraries {
+        println!("{}\t{}", name, path.display());
+    }
     Ok(())
 }
 
-fn detect_libraries(dir: &Path, library_dirs: &mut HashMap<String, PathBuf>, shell: &mut MultiShell) -> Result<()> {
+fn cache_run(args: &[String], config: &mut Config) -> Result<()> {
+    let cache_dir = cache::cache_dir();
+    match args.first().map(String::as_str) {
+        Some("info") => {
+            let mut total = 0;
+            for &(_, dirs) in cache::CACHES {
+                for dir in dirs {
+                    let size = cache::dir_size(&cache_dir.join(dir));
+                    total += size;
+                    println!("{:<16} {} bytes", format!("{}:", dir), size);
+                }
+            }
+            println!("{:<16} {} bytes in {}", "total:", total, cache_dir.display());
+            Ok(())
+        }
+        Some("clear") => {
+            let mut dirs = Vec::new();
+            for option in &args[1..] {
+                let caches = cache::CACHES.iter().filter(|&&(name, _)| {
+                    option == "--all" || *option == format!("--{}", name)
+                }).collect::<Vec<_>>();
+                if caches.is_empty() {
+                    bail!(ErrorKind::InvalidArgument(format!("Unknown cache '{}' (expected `--prefs`, `--targets`, \
+                                                              `--includes` or `--all`)", option)));
+                }
+                dirs.extend(caches.into_iter().flat_map(|&(_, dirs)| dirs.iter()));
+            }
+            if dirs.is_empty() {
+                bail!(ErrorKind::InvalidArgument("Expected `--prefs`, `--targets`, `--includes` or `--all` after \
+                                                  `cache clear`".to_string()));
+            }
+
+            let mut removed = false;
+            for dir in dirs {
+                let path = cache_dir.join(dir);
+                if path.is_dir() {
+                    let size = cache::dir_size(&path);
+                    fs::remove_dir_all(&path).chain_err(|| format!("Could not remove '{}'", path.display()))?;
+                    config.status("Removed", format_args!("{} ({} bytes)", path.display(), size))?;
+                    removed = true;
+                }
+            }
+            if !removed {
+                config.status("Cache", "nothing to remove")?;
+            }
+            Ok(())
+        }
+        Some(request) => {
+            bail!(ErrorKind::InvalidArgument(format!("Unknown cache request '{}' (expected `info` or `clear`)", request)))
+        }
+        None => bail!(ErrorKind::InvalidArgument("Expected `info` or `clear` after `cache`".to_string()))
+    }
+}
+
+fn versions_run(config: &mut Config) -> Result<()> {
+    let home = config.home();
+    let mut tools = vec![
+        ("arduino-builder", version_command(builder::program(home.as_ref().map(PathBuf::as_path)), "-version")),
+        ("arduino-cli", version_command("arduino-cli", "version")),
+        ("cargo", version_command("cargo", "--version")),
+        ("rustc", version_command("rustc", "--version")),
+        ("xargo", version_command("xargo", "--version"))
+    ];
+
+    if let Some(builder) = config.create_builder()? {
+        match retrieve_prefs(&builder, config) {
+            Ok(prefs) => {
+                for &(name, key) in &[("C compiler", "recipe.c.o.pattern"), ("C++ compiler", "recipe.cpp.o.pattern")] {
+                    if let Some(recipe) = prefs.get::<String>(key) {
+                        let (command, _) = build_config::split_command_line(&recipe);
+                        tools.push((name, version_command(command, "--version")));
+                    }
+                }
+            }
+            Err(error) => config.warn(format_args!("Could not retrieve the board's compilers: {}", error))?
+        }
+    }
+
+    for (name, command) in tools {
+        let version = tool_version(&command);
+        println!("{:<16} {}", format!("{}:", name), version.as_ref().map_or("not found", String::as_str));
+
+        if name == "rustc" && version.as_ref().map_or(false, |version| !version.contains("nightly") && !version.contains("-dev")) {
+            config.warn("rustc is not a nightly toolchain, which carguino and xargo require")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn doctor_run(config: &mut Config) -> Result<()> {
+    let mut checks = Vec::<(bool, String, &str)>::new();
+
+    let home = config.home();
+    if let Some(ref home) = home {
+        checks.push((home.is_dir(), format!("Arduino home {} exists", home.display()),
+                     "Set ARDUINO_HOME or `home` in the [arduino-builder] configuration to the Arduino IDE's directory"));
+    }
+    let backend = Backend::from_name(config.backend()).and_then(|backend| backend).unwrap_or_else(|| {
+        Backend::detect(home.as_ref().map(PathBuf::as_path))
+    });
+    match backend {
+        Backend::ArduinoBuilder => {
+            let arduino_builder = builder::program(home.as_ref().map(PathBuf::as_path));
+            checks.push((tool_version(&version_command(&arduino_builder, "-version")).is_some(),
+                         format!("{} can be run", arduino_builder.display()),
+                         "Install the Arduino IDE and set ARDUINO_HOME, or put arduino-builder on the PATH"));
+        }
+        Backend::ArduinoCli => {
+            checks.push((tool_version(&version_command("arduino-cli", "version")).is_some(),
+                         "arduino-cli can be run".to_string(), "Install arduino-cli and put it on the PATH"));
+        }
+    }
+
+    match config.create_builder()? {
+        Some(builder) => {
+            checks.push((true, format!("target-board {} is configured", config.target_board().unwrap()), ""));
+            match retrieve_prefs(&builder, config) {
+                Ok(prefs) => {
+                    checks.push((true, "the board's build settings can be retrieved".to_string(), ""));
+                    for key in &["build.arch", "build.mcu", "recipe.c.o.pattern", "recipe.cpp.o.pattern", "recipe.c.combine.pattern"] {
+                        checks.push((prefs.get::<String>(key).is_some(), format!("the platform defines '{}'", key),
+                                     "Reinstall the board's platform in the Arduino IDE's boards manager"));
+                    }
+                    checks.push((cache::toolchain_exists(&prefs), "the board's compilers exist".to_string(),
+                                 "Reinstall the board's platform in the Arduino IDE's boards manager"));
+                }
+                Err(error) => {
+                    checks.push((false, format!("the board's build settings can be retrieved ({})", error),
+                                 "Check the target-board's name and the board's platform installation"));
+                }
+            }
+        }
+        None => {
+            checks.push((false, "a target-board is configured".to_string(),
+                         "Pass '--target-board' or set `target-board` in .carguino/config"));
+        }
+    }
+
+    checks.extend(rust_toolchain_checks());
+
+    for &(passed, ref description, hint) in &checks {
+        if passed {
+            println!("[ok]   {}", description);
+        } else {
+            println!("[FAIL] {}", description);
+            println!("       {}", hint);
+        }
+    }
+
+    let failures = checks.iter().filter(|&&(passed, _, _)| !passed).count();
+    if failures > 0 {
+        bail!("{} of {} checks failed", failures, checks.len());
+    }
+    Ok(())
+}
+
+// Everything a build needs from the Rust toolchain, with a hint on how to fix each problem.
+fn rust_toolchain_checks() -> Vec<(bool, String, &'static str)> {
+    let mut checks = Vec::new();
+    let rustc_version = rustc_version();
+    checks.push((rustc_version.as_ref().map_or(false, |version| version.contains("nightly") || version.contains("-dev")),
+                 format!("rustc is a nightly toolchain ({})", rustc_version.as_ref().map_or("not found", String::as_str)),
+                 "Install a nightly toolchain with `rustup toolchain install nightly`"));
+    let rust_src = util::process("rustc").arg("--print").arg("sysroot").exec_with_output().ok().map_or(false, |output| {
+        Path::new(String::from_utf8_lossy(&output.stdout).trim()).join("lib/rustlib/src/rust").is_dir()
+    });
+    checks.push((rust_src, "the Rust sources are installed".to_string(), "Run `rustup component add rust-src`"));
+    checks.push((tool_version(&version_command("xargo", "--version")).is_some(), "xargo can be run".to_string(),
+                 "Run `cargo install xargo`"));
+    checks
+}
+
+// Without these, rustc and xargo fail with errors that do not point at the actual problem.
+fn check_rust_toolchain(config: &mut Config) -> Result<()> {
+    config.explain("Checking that the Rust toolchain can build the sysroot for a custom target")?;
+    let checks = rust_toolchain_checks();
+    if let Some(&(_, ref description, hint)) = checks.iter().find(|&&(passed, _, _)| !passed) {
+        bail!("The Rust toolchain can not build for the board; the check that {} failed.\n{}", description, hint);
+    }
+    Ok(())
+}
+
+// The checks run rustc and xargo, so they are only done once a build failed, to replace an error that may not point at
+// the actual problem.
+fn explain_build_failure(error: Error, config: &mut Config) -> Error {
+    match check_rust_toolchain(config) {
+        Ok(()) => error,
+        Err(toolchain_error) => toolchain_error
+    }
+}
+
+// The version keys the cached built-in target specifications, so rustc is only asked for it once per invocation.
+fn rustc_version() -> Option<String> {
+    lazy_static! {
+        static ref RUSTC_VERSION: Option<String> = tool_version(&version_command("rustc", "--version"));
+    }
+    RUSTC_VERSION.clone()
+}
+
+fn version_command<T: AsRef<OsStr>>(program: T, flag: &str) -> ProcessBuilder {
+    let mut command = util::process(program);
+    command.arg(flag);
+    command
+}
+
+// Some tools (e.g. xargo) print their version to stderr.
+fn tool_version(command: &ProcessBuilder) -> Option<String> {
+    command.exec_with_output().ok().and_then(|output| {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        stdout.lines().chain(stderr.lines()).map(str::trim).find(|line| !line.is_empty()).map(String::from)
+    })
+}
+
+fn retrieve_prefs(builder: &Builder, config: &mut Config) -> Result<Preferences> {
+    let prefs_dir = cache::cache_dir().join("prefs");
+    let prefs_file = prefs_dir.join(builder.fingerprint()).with_extension("txt");
+    let cached_prefs = match cache::load_prefs(&prefs_file) {
+        Some(ref prefs) if !cache::toolchain_exists(prefs) => {
+            config.verbose_status("Relocated", format_args!("toolchain; discarding cached build settings"))?;
+
+            None
+        }
+        prefs => prefs
+    };
+
+    if let Some(prefs) = cached_prefs {
+        config.verbose_status("Fresh", format_args!("build settings"))?;
+
+        return Ok(prefs);
+    }
+
+    config.verbose_status("Retrieving", format_args!("build settings"))?;
+    config.explain(format_args!("Dumping the board's build preferences (compilers, flags and paths) from {}",
+                                builder.backend_name()))?;
+
+    let temp_dir = match config.temp_dir() {
+        Some(dir) => TempDir::new_in(dir, "carguino"),
+        None => TempDir::new("carguino")
+    }.chain_err(|| "Could not create temporary directory")?;
+    let temp_file = temp_dir.path().join("project.c");
+    File::create(&temp_file).chain_err(|| "Could not create temporary project file")?;
+
+    let prefs = builder.dump_prefs(&temp_file)?;
+    fs::create_dir_all(&prefs_dir).chain_err(|| "Could not create preferences cache directory")?;
+    prefs.save(&prefs_file)?;
+    Ok(prefs)
+}
+
+fn target_arch_mcu(prefs: &Preferences) -> Result<(String, String)> {
+    let target_arch = prefs.get::<String>("build.arch")
+                                .map(|s| s.to_lowercase())
+                                .map_or_else(|| Err("'build.arch' missing from preferences"), Ok)?;
+    let target_mcu = prefs.get::<String>("build.mcu")
+                               .map_or_else(|| Err("'build.mcu' missing from preferences"), Ok)?;
+    Ok((target_arch, target_mcu))
+}
+
+fn is_release(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--release" || arg == "--profile=release") ||
+    args.windows(2).any(|pair| pair[0] == "--profile" && pair[1] == "release")
+}
+
+// The package's features that cargo enables for the arguments, including the ones enabled by other features. Features
+// of dependencies (`dependency/feature`) are left out.
+fn active_features(declared: &Value, args: &[String]) -> BTreeSet<String> {
+    let mut requested = Vec::new();
+    let mut default_features = true;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--all-features" {
+            requested.extend(declared.as_object().into_iter().flat_map(|features| features.keys().cloned()));
+        } else if arg == "--no-default-features" {
+            default_features = false;
+        } else if arg == "--features" {
+            requested.extend(iter.next().into_iter().flat_map(|features| split_features(features)));
+        } else if arg.starts_with("--features=") {
+            requested.extend(split_features(&arg["--features=".len()..]));
+        }
+    }
+    if default_features {
+        requested.push("default".to_string());
+    }
+
+    let mut active = BTreeSet::new();
+    while let Some(feature) = requested.pop() {
+        if feature.contains('/') || active.contains(&feature) {
+            continue;
+        }
+        if let Some(enabled) = declared[feature.as_str()].as_array() {
+            requested.extend(enabled.iter().filter_map(Value::as_str).map(String::from));
+        }
+        active.insert(feature);
+    }
+    active
+}
+
+fn split_features(features: &str) -> Vec<String> {
+    features.split(|c: char| c == ',' || c.is_whitespace()).filter(|feature| !feature.is_empty())
+            .map(String::from).collect()
+}
+
+// Checking and documenting do not produce any firmware to post-process.
+fn links_firmware(command: &str) -> bool {
+    !["check", "clippy", "doc", "rustdoc"].contains(&command)
+}
+
+fn default_rustflags(arch: &str) -> Vec<&'static str> {
+    match arch {
+        "avr" => vec!["-C opt-level=s"],
+        _ => vec![]
+    }
+}
+
+// In a workspace, or with path dependencies, cargo lists more packages than the one being built. Like cargo, the
+// project is the package closest to the directory of the manifest path or the current directory.
+fn project_package<'a>(metadata: &'a Value, manifest_dir: &Path) -> Option<&'a Value> {
+    metadata["packages"].as_array().into_iter().flat_map(|packages| packages.iter()).filter_map(|package| {
+        package["manifest_path"].as_str().and_then(|path| Path::new(path).parent()).map(|dir| (package, dir))
+    }).filter(|&(_, dir)| manifest_dir.starts_with(dir)).max_by_key(|&(_, dir)| dir.components().count())
+      .map(|(package, _)| package)
+}
+
+fn target_dir(metadata: &Value, project_dir: &Path) -> PathBuf {
+    metadata["target_directory"].as_str().map(PathBuf::from).or_else(|| {
+        env::var_os("CARGO_TARGET_DIR").map(PathBuf::from)
+    }).unwrap_or_else(|| project_dir.join("target"))
+}
+
+// Libraries in the configured directories override the platform's libraries of the same name.
+fn available_libraries(prefs: &Preferences, config: &mut Config) -> Result<HashMap<String, PathBuf>> {
+    let platform_dir = prefs.get::<String>("runtime.platform.path")
+                                 .map(PathBuf::from)
+                                 .map_or_else(|| Err("'runtime.platform.path' missing from preferences"), Ok)?;
+
+    let mut library_paths = HashMap::new();
+    detect_libraries(&platform_dir.join("libraries"), &mut library_paths, config)?;
+    for dir in config.libraries() {
+        detect_libraries(&dir, &mut library_paths, config)?;
+    }
+    Ok(library_paths)
+}
+
+fn detect_libraries(dir: &Path, library_dirs: &mut HashMap<String, PathBuf>, config: &mut Config) -> Result<()> {
     match fs::read_dir(dir) {
         Ok(iter) => {
             for entry in iter {
@@ -265,13 +1509,13 @@ fn detect_libraries(dir: &Path, library_dirs: &mut HashMap<String, PathBuf>, she
                 if path.is_dir() {
                     let library_name = path.file_name().unwrap().to_string_lossy().to_string();
                     if library_dirs.insert(library_name.clone(), path).is_some() {
-                        shell.warn(format_args!("Library directory for '{}' overridden", library_name)).unwrap();
+                        config.warn(format_args!("Library directory for '{}' overridden", library_name))?;
                     }
                 }
             }
         }
         Err(error) => {
-            shell.warn(format_args!("Skipping library directory '{}': {}", dir.display(), error)).unwrap();
+            config.warn(format_args!("Skipping library directory '{}': {}", dir.display(), error))?;
         }
     }
     Ok(())
@@ -283,16 +1527,32 @@ struct LinkerOptions {
     script: Option<String>,
     specs: Vec<String>,
     library_search_path: Vec<String>,
-    libraries: Vec<String>,
+    group: Option<(String, String)>,
+    late_link_args: Vec<String>,
     platform_options: Vec<String>
 }
 
+fn board_linker_options(prefs: &Preferences, config: &Config) -> Result<LinkerOptions> {
+    let linker_recipe = prefs.get::<String>("recipe.c.combine.pattern")
+                             .map_or_else(|| Err("'recipe.c.combine.pattern' missing from preferences"), Ok)?;
+
+    let mut linker_options = parse_linker_options(&linker_recipe);
+    linker_options.platform_options.extend(config.defsyms().iter().map(|symbol| format!("-Wl,--defsym={}", symbol)));
+    linker_options.platform_options.extend(config.link_args().iter().cloned());
+    // rustc passes `--gc-sections` itself, which the last of the two options overrides.
+    if !config.gc_sections() {
+        linker_options.late_link_args.push("-Wl,--no-gc-sections".to_string());
+    }
+    Ok(linker_options)
+}
+
 fn parse_linker_options(command_line: &str) -> LinkerOptions {
     let (command, args) = build_config::split_command_line(command_line);
     let mut result = LinkerOptions {
         command: command.to_str().unwrap().to_string(),
         .. Default::default()
     };
+    let mut whole_archive = None;
     let mut iter = args.into_iter();
     while let Some(arg) = iter.next() {
         match arg.as_str() {
@@ -325,25 +1585,219 @@ fn parse_linker_options(command_line: &str) -> LinkerOptions {
             }
 
             "-l" => {
-                result.libraries.push(iter.next().unwrap().clone());
+                result.late_link_args.push(format!("-l{}", iter.next().unwrap()));
             }
             arg if arg.starts_with("-l") => {
-                result.libraries.push(arg[2..].to_string());
+                result.late_link_args.push(arg.to_string());
+            }
+
+            "--start-group" | "-Wl,--start-group" => {
+                result.group = Some((arg.clone(), arg.replace("--start-group", "--end-group")));
+            }
+            "--end-group" | "-Wl,--end-group" => {}
+
+            "--whole-archive" | "-Wl,--whole-archive" => {
+                whole_archive = Some((arg.clone(), result.late_link_args.len()));
+            }
+            "--no-whole-archive" | "-Wl,--no-whole-archive" => {
+                if let Some((start, position)) = whole_archive.take() {
+                    wrap_libraries(&mut result.late_link_args, position, start, arg.clone());
+                }
             }
 
             arg if arg.starts_with("-m") => {
                 result.platform_options.push(arg.to_string());
             }
 
+            // GCC looks up the device specs (e.g. for `-mmcu`) in the directories given by `-B`.
+            // A trailing `-B` without a directory is left out.
+            "-B" => {
+                if let Some(dir) = iter.next() {
+                    result.platform_options.push(format!("-B{}", dir));
+                }
+            }
+            arg if arg.starts_with("-B") => {
+                result.platform_options.push(arg.to_string());
+            }
+
             _ => {}
         }
     }
+    if let Some((start, position)) = whole_archive {
+        let end = start.replace("--whole-archive", "--no-whole-archive");
+        wrap_libraries(&mut result.late_link_args, position, start, end);
+    }
     result
 }
 
+// rustc already links the static libraries of the package, like the core, as whole archives, so only a whole-archive
+// region around libraries of the recipe is kept, around the same libraries.
+fn wrap_libraries(late_link_args: &mut Vec<String>, position: usize, start: String, end: String) {
+    if late_link_args.len() > position {
+        late_link_args.insert(position, start);
+        late_link_args.push(end);
+    }
+}
+
 fn create_target_spec(config: &mut Config, linker_options: &LinkerOptions, targets_dir: &Path,
-                      arch: &str, cpu: &str, ) -> Result<(&'static str, String)> {
-    let target = match arch {
+                      arch: &str, mcu: &str, ) -> Result<(&'static str, String)> {
+    let cpu = target_cpu(config, arch, mcu)?;
+    let target = target_triple(arch, &cpu)?;
+    let spec_name = spec_name(config.target_board().unwrap());
+    let spec_path = targets_dir.join(&spec_name).with_extension("json");
+
+    let previous_spec = File::open(&spec_path).ok().and_then(|file| {
+        serde_json::from_reader::<_, Value>(file).ok()
+    });
+
+    // The built-in target only changes with the compiler, so it is kept per rustc version.
+    let base_spec_path = rustc_version().map(|version| {
+        cache::base_spec_file(&version, target)
+    });
+    let cached_base_spec = base_spec_path.as_ref().and_then(|path| File::open(path).ok()).and_then(|file| {
+        serde_json::from_reader::<_, Value>(file).ok()
+    });
+
+    let mut spec = if let Some(base_spec) = cached_base_spec {
+        config.verbose_status("Fresh", format_args!("built-in target specification {}", target))?;
+        base_spec
+    } else {
+        let mut rustc = util::process("rustc");
+        rustc.arg("-Z").arg("unstable-options")
+            .arg("--target").arg(target)
+            .arg("--print").arg("target-spec-json");
+
+        config.explain(format_args!("Printing the built-in `{}` target as the base of the board's target specification", target))?;
+        config.verbose_status("Running", &rustc)?;
+
+        let output = rustc.exec_with_output()?;
+        if let Some(ref path) = base_spec_path {
+            fs::create_dir_all(path.parent().unwrap()).and_then(|_| File::create(path))
+                                                     .and_then(|mut file| file.write_all(&output.stdout))
+                                                     .chain_err(|| "Could not cache the built-in target specification")?;
+        }
+        serde_json::from_slice::<Value>(&output.stdout).unwrap()
+    };
+    spec["is-builtin"] = Value::Bool(false);
+    // A different linker only replaces the platform's one in the specification; binutils are still found next to the latter.
+    spec["linker"] = Value::String(match config.linker() {
+        Some(linker) => linker_path(linker)?,
+        None => linker_options.command.clone()
+    });
+    spec["linker-is-gnu"] = Value::Bool(true);
+    spec["no-default-libraries"] = Value::Bool(false);
+    spec["cpu"] = Value::String(cpu);
+
+    let mut pre_link_args = spec["pre-link-args"].as_array().cloned().unwrap_or_default();
+    pre_link_args.extend(linker_options.specs.iter().map(|specs| {
+        Value::String(format!("-specs={}", specs))
+    }));
+    pre_link_args.extend(linker_options.platform_options.iter().map(|option| {
+        Value::String(option.clone())
+    }));
+    // Without the exact device the linker would use the wrong memory layout.
+    if arch == "avr" && !linker_options.platform_options.iter().any(|option| option.starts_with("-mmcu=")) {
+        pre_link_args.push(Value::String(format!("-mmcu={}", mcu)));
+    }
+    if let Some(ref script) = linker_options.script {
+        pre_link_args.push(Value::String(format!("-T{}", script)));
+    }
+    pre_link_args.extend(linker_options.library_search_path.iter().map(|lib_path| {
+        Value::String(format!("-L{}", lib_path))
+    }));
+    // The group wraps the Rust objects and all libraries, like the objects and archives in the combine recipe.
+    if let Some((ref start, _)) = linker_options.group {
+        pre_link_args.push(Value::String(start.clone()));
+    }
+    spec["pre-link-args"] = Value::Array(pre_link_args);
+
+    let mut late_link_args = spec["late-link-args"].as_array().cloned().unwrap_or_default();
+    late_link_args.extend(linker_options.late_link_args.iter().cloned().map(Value::String));
+    if let Some((_, ref end)) = linker_options.group {
+        late_link_args.push(Value::String(end.clone()));
+    }
+    spec["late-link-args"] = Value::Array(late_link_args);
+
+    if previous_spec.as_ref() == Some(&spec) {
+        config.verbose_status("Fresh", format_args!("target specification {}", spec_name))?;
+    } else {
+        if let Some(ref previous_spec) = previous_spec {
+            config.verbose_status("Regenerating", format_args!("target specification {}", spec_name))?;
+
+            if config.extra_verbose() {
+                for change in diff_spec(previous_spec, &spec) {
+                    config.status("Changed", change)?;
+                }
+            }
+        }
+
+        let mut spec_file = File::create(&spec_path).chain_err(|| "Could not create target spec file")?;
+        serde_json::to_writer_pretty(&mut spec_file, &spec).chain_err(|| "Could not serialize to target spec file")?;
+    }
+
+    Ok((target, spec_name))
+}
+
+// Paths are made absolute, as rustc runs the linker from the package's directory.
+fn linker_path(linker: &Path) -> Result<String> {
+    if linker.components().count() > 1 {
+        let path = fs::canonicalize(linker).chain_err(|| format!("The linker '{}' does not exist", linker.display()))?;
+        return Ok(path.to_string_lossy().into_owned());
+    }
+
+    let file_name = format!("{}{}", linker.display(), env::consts::EXE_SUFFIX);
+    let exists = env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| dir.join(linker).is_file() || dir.join(&file_name).is_file())
+    });
+    if !exists {
+        bail!("The linker '{}' was not found in PATH", linker.display());
+    }
+    Ok(linker.to_string_lossy().into_owned())
+}
+
+fn target_cpu(config: &mut Config, arch: &str, mcu: &str) -> Result<String> {
+    match llvm_cpu(arch, mcu) {
+        Some(cpu) => Ok(cpu.to_string()),
+        None => {
+            config.warn(format!("Unknown MCU '{}'; passing it on to rustc as the CPU as-is", mcu))?;
+            Ok(mcu.to_string())
+        }
+    }
+}
+
+// Arduino names the MCU as GCC does, which is usually, but not always, how LLVM names the CPU.
+fn llvm_cpu(arch: &str, mcu: &str) -> Option<&'static str> {
+    const AVR_CPUS: &'static [&'static str] = &[
+        "at90usb162", "at90usb1286", "atmega8", "atmega8u2", "atmega16u2", "atmega32u4", "atmega168", "atmega168p",
+        "atmega328", "atmega328p", "atmega644p", "atmega1280", "atmega1284p", "atmega2560", "attiny44", "attiny45",
+        "attiny84", "attiny85"
+    ];
+    const ARM_CPUS: &'static [&'static str] = &["cortex-m0", "cortex-m0plus", "cortex-m1", "cortex-m3", "cortex-m4",
+                                                "cortex-m7"];
+
+    let mcu = mcu.to_lowercase();
+    match (arch, mcu.as_str()) {
+        // LLVM does not know the PB variant, whose core is that of the ATmega328P.
+        ("avr", "atmega328pb") => Some("atmega328p"),
+        ("avr", mcu) => AVR_CPUS.iter().find(|&&cpu| cpu == mcu).cloned(),
+        (_, "cortex-m0+") => Some("cortex-m0plus"),
+        (_, mcu) => ARM_CPUS.iter().find(|&&cpu| cpu == mcu).cloned()
+    }
+}
+
+// The cfgs that the `[target.'cfg(...)']` sections of cargo's configuration can test for the board.
+fn target_cfgs(llvm_target: &str, arch: &str, mcu: &str) -> Vec<Cfg> {
+    let target_arch = if llvm_target.starts_with("thumb") { "arm" } else { llvm_target.split('-').next().unwrap() };
+    vec![
+        Cfg::KeyPair("target_arch".to_string(), target_arch.to_string()),
+        Cfg::KeyPair("target_os".to_string(), "none".to_string()),
+        Cfg::KeyPair("arduino_arch".to_string(), arch.to_string()),
+        Cfg::KeyPair("arduino_mcu".to_string(), mcu.to_string())
+    ]
+}
+
+fn target_triple(arch: &str, cpu: &str) -> Result<&'static str> {
+    Ok(match arch {
         "avr" => "avr-atmel-none",
         "samd" => "thumbv6m-none-eabi",
         "sam" => match cpu {
@@ -355,60 +1809,160 @@ fn create_target_spec(config: &mut Config, linker_options: &LinkerOptions, targe
         arch => {
             bail!("Unsupported architecture: {}", arch);
         }
-    };
+    })
+}
 
-    let spec_name = {
-        let board = config.target_board().unwrap();
-        let arch = board.arch().to_lowercase().replace('-', "_");
-        let vendor = board.vendor().to_lowercase().replace('-', "_");
-        let name = board.board().to_lowercase().replace('-', "_");
+fn spec_name(board: &BoardInfo) -> String {
+    let arch = board.arch().to_lowercase().replace('-', "_");
+    let vendor = board.vendor().to_lowercase().replace('-', "_");
+    let name = board.board().to_lowercase().replace('-', "_");
 
-        format!("{}-{}-{}", arch, vendor, name)
-    };
-    let spec_path = targets_dir.join(&spec_name).with_extension("json");
+    // Menu options can change the MCU, so boards with different options must not share a target or its sysroot.
+    let params = board.params().iter().map(|(key, value)| {
+        format!("-{}_{}", key, value).to_lowercase().replace(|c: char| !c.is_alphanumeric() && c != '-', "_")
+    }).collect::<String>();
 
-    if !spec_path.is_file() {
-        let mut rustc = util::process("rustc");
-        rustc.arg("-Z").arg("unstable-options")
-            .arg("--target").arg(target)
-            .arg("--print").arg("target-spec-json");
+    format!("{}-{}-{}{}", arch, vendor, name, params)
+}
 
-        config.shell().verbose(|shell| {
-            shell.status_ext("Running", &rustc)
-        })?;
+const MANAGED_SPEC_FIELDS: &'static [&'static str] = &[
+    "is-builtin", "linker", "linker-is-gnu", "no-default-libraries", "cpu", "pre-link-args", "late-link-args"
+];
 
-        let output = rustc.exec_with_output()?;
-        let mut spec = serde_json::from_slice::<Value>(&output.stdout).unwrap();
-        spec["is-builtin"] = Value::Bool(false);
-        spec["linker"] = Value::String(linker_options.command.clone());
-        spec["linker-is-gnu"] = Value::Bool(true);
-        spec["no-default-libraries"] = Value::Bool(false);
-        spec["cpu"] = Value::String(cpu.to_string());
-
-        let mut pre_link_args = spec["pre-link-args"].as_array().cloned().unwrap_or_default();
-        pre_link_args.extend(linker_options.specs.iter().map(|specs| {
-            Value::String(format!("-specs={}", specs))
-        }));
-        pre_link_args.extend(linker_options.platform_options.iter().map(|option| {
-            Value::String(option.clone())
-        }));
-        if let Some(ref script) = linker_options.script {
-            pre_link_args.push(Value::String(format!("-T{}", script)));
-        }
-        pre_link_args.extend(linker_options.library_search_path.iter().map(|lib_path| {
-            Value::String(format!("-L{}", lib_path))
-        }));
-        spec["pre-link-args"] = Value::Array(pre_link_args);
-
-        let mut late_link_args = spec["late-link-args"].as_array().cloned().unwrap_or_default();
-        late_link_args.extend(linker_options.libraries.iter().map(|lib| {
-            Value::String(format!("-l{}", lib))
-        }));
-        spec["late-link-args"] = Value::Array(late_link_args);
+fn diff_spec(old_spec: &Value, new_spec: &Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    for &field in MANAGED_SPEC_FIELDS {
+        let (old_value, new_value) = (&old_spec[field], &new_spec[field]);
+        if old_value == new_value {
+            continue;
+        }
 
-        let mut spec_file = File::create(&spec_path).chain_err(|| "Could not create target spec file")?;
-        serde_json::to_writer_pretty(&mut spec_file, &spec).chain_err(|| "Could not serialize to target spec file")?;
+        match (old_value.as_array(), new_value.as_array()) {
+            (Some(old_items), Some(new_items)) => {
+                for item in old_items.iter().filter(|item| !new_items.contains(item)) {
+                    changes.push(format!("{}: - {}", field, item));
+                }
+                for item in new_items.iter().filter(|item| !old_items.contains(item)) {
+                    changes.push(format!("{}: + {}", field, item));
+                }
+            }
+            _ => changes.push(format!("{}: {} -> {}", field, old_value, new_value))
+        }
     }
+    changes
+}
 
-    Ok((target, spec_name))
+#[cfg(test)]
+mod tests {
+    use super::{collect_artifacts, jobs, parse_linker_options, project_package, run_parallel};
+
+    use serde_json::{self, Value};
+
+    use std::path::{Path, PathBuf};
+
+    const PACKAGE_ID: &'static str = "blink 0.1.0 (path+file:///blink)";
+
+    fn artifact(package_id: &str, name: &str, kind: &str, test: bool, filename: &str) -> String {
+        format!(r#"{{"reason": "compiler-artifact", "package_id": "{}", "target": {{"kind": ["{}"], "name": "{}"}}, "profile": {{"test": {}}}, "filenames": ["{}"]}}"#,
+                package_id, kind, name, test, filename)
+    }
+
+    #[test]
+    fn only_binaries_of_the_package_are_collected() {
+        let stdout = [
+            artifact("core 0.0.0 (registry)", "core", "lib", false, "/target/libcore.rlib"),
+            artifact(PACKAGE_ID, "blink", "lib", false, "/target/libblink.rlib"),
+            artifact(PACKAGE_ID, "blink", "bin", false, "/target/blink"),
+            "Compiling blink v0.1.0".to_string(),
+            artifact(PACKAGE_ID, "blink", "bin", true, "/target/blink-0123456789abcdef"),
+            artifact(PACKAGE_ID, "leds", "test", true, "/target/leds-0123456789abcdef"),
+            artifact(PACKAGE_ID, "timing", "bench", false, "/target/timing-0123456789abcdef"),
+            r#"{"reason": "build-script-executed", "package_id": "blink 0.1.0 (path+file:///blink)"}"#.to_string(),
+            artifact(PACKAGE_ID, "fade", "bin", false, "/target/fade")
+        ].join("\n");
+
+        let (binaries, other_targets) = collect_artifacts(stdout.as_bytes(), PACKAGE_ID);
+        assert_eq!(binaries, vec![PathBuf::from("/target/blink"), PathBuf::from("/target/fade")]);
+        assert_eq!(other_targets, vec!["blink (test)", "leds (test)", "timing (bench)"]);
+    }
+
+    #[test]
+    fn grouped_combine_recipe_wraps_the_libraries() {
+        let options = parse_linker_options("\"/tools/arm-none-eabi-gcc\" -mcpu=cortex-m3 -mthumb -Os -Wl,--gc-sections \
+                                            \"-T/variants/arduino_due_x/linker_scripts/gcc/flash.ld\" \
+                                            \"-Wl,-Map,/build/blink.map\" -o \"/build/blink.elf\" -Wl,--start-group \
+                                            \"/build/syscalls_sam3.c.o\" \"/build/core.a\" -lm -lgcc -Wl,--end-group");
+        assert_eq!(options.command, "/tools/arm-none-eabi-gcc");
+        assert_eq!(options.script, Some("/variants/arduino_due_x/linker_scripts/gcc/flash.ld".to_string()));
+        assert_eq!(options.platform_options, vec!["-mcpu=cortex-m3", "-mthumb"]);
+        assert_eq!(options.late_link_args, vec!["-lm", "-lgcc"]);
+        assert_eq!(options.group, Some(("-Wl,--start-group".to_string(), "-Wl,--end-group".to_string())));
+
+        let options = parse_linker_options("gcc --start-group -lm --end-group");
+        assert_eq!(options.group, Some(("--start-group".to_string(), "--end-group".to_string())));
+        assert_eq!(parse_linker_options("gcc -lm").group, None);
+    }
+
+    #[test]
+    fn device_spec_directories_are_kept() {
+        let options = parse_linker_options("avr-gcc -B /packs/atmega4809 -mmcu=atmega4809 -B/packs/common");
+        assert_eq!(options.platform_options, vec!["-B/packs/atmega4809", "-mmcu=atmega4809", "-B/packs/common"]);
+
+        let options = parse_linker_options("avr-gcc -mmcu=atmega4809 -B");
+        assert_eq!(options.platform_options, vec!["-mmcu=atmega4809"]);
+    }
+
+    #[test]
+    fn whole_archive_regions_are_kept_around_their_libraries() {
+        let options = parse_linker_options("gcc -o blink.elf -Wl,--whole-archive \"/build/core.a\" -Wl,--no-whole-archive \
+                                            -lc -Wl,--whole-archive -lnosys -lrdimon -Wl,--no-whole-archive -lm");
+        assert_eq!(options.late_link_args, vec!["-lc", "-Wl,--whole-archive", "-lnosys", "-lrdimon",
+                                                "-Wl,--no-whole-archive", "-lm"]);
+
+        let options = parse_linker_options("gcc -lc --whole-archive -lnosys");
+        assert_eq!(options.late_link_args, vec!["-lc", "--whole-archive", "-lnosys", "--no-whole-archive"]);
+    }
+
+    #[test]
+    fn project_is_the_package_closest_to_the_manifest_dir() {
+        let metadata = serde_json::from_str::<Value>(r#"{"packages": [
+            {"id": "sensor 0.1.0", "manifest_path": "/workspace/sensor/Cargo.toml"},
+            {"id": "workspace 0.1.0", "manifest_path": "/workspace/Cargo.toml"},
+            {"id": "blink 0.1.0", "manifest_path": "/workspace/blink/Cargo.toml"}
+        ]}"#).unwrap();
+        let project_id = |dir: &str| project_package(&metadata, Path::new(dir)).map(|package| package["id"].clone());
+
+        assert_eq!(project_id("/workspace/blink"), Some(Value::String("blink 0.1.0".to_string())));
+        assert_eq!(project_id("/workspace/blink/src/bin"), Some(Value::String("blink 0.1.0".to_string())));
+        assert_eq!(project_id("/workspace/docs"), Some(Value::String("workspace 0.1.0".to_string())));
+        assert_eq!(project_id("/elsewhere"), None);
+    }
+
+    #[test]
+    fn jobs_are_taken_from_the_arguments() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(jobs(&args(&["--release", "-j", "3"])), 3);
+        assert_eq!(jobs(&args(&["-j2"])), 2);
+        assert_eq!(jobs(&args(&["--jobs=5", "--release"])), 5);
+        assert_eq!(jobs(&args(&["--jobs", "0"])), 1);
+        assert!(jobs(&args(&["--release"])) >= 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failures_are_reported_in_the_order_of_the_commands() {
+        use cargo::util;
+
+        let commands = ["true", "false", "true", "false", "true"].iter().enumerate().map(|(index, program)| {
+            let mut command = util::process(program);
+            command.arg(index.to_string());
+            command
+        }).collect::<Vec<_>>();
+        let failures = run_parallel(commands, 2);
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].contains("false 1"), "{:?}", failures);
+        assert!(failures[1].contains("false 3"), "{:?}", failures);
+
+        assert!(run_parallel(Vec::new(), 4).is_empty());
+    }
 }
@@ -0,0 +1,290 @@
+use board::BoardInfo;
+use error::{Result, ResultExt};
+
+use carguino_build::Preferences;
+
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(any(target_os = "linux", target_os = "macos"))] use std::fs;
+#[cfg(target_os = "linux")] use std::fs::File;
+#[cfg(target_os = "linux")] use std::io::Read;
+#[cfg(target_os = "linux")] use std::path::Path;
+
+#[derive(Debug)]
+struct SerialPort {
+    name: String,
+    usb_id: Option<(u16, u16)>
+}
+
+pub fn usb_ids(prefs: &Preferences) -> Vec<(u16, u16)> {
+    // Boards list their USB IDs either as `build.vid`/`build.pid` or as numbered `vid.N`/`pid.N` pairs.
+    let mut ids = vec![(prefs.get::<String>("build.vid"), prefs.get::<String>("build.pid"))];
+    ids.extend(prefs.get_indexed("vid").into_iter().map(|(index, vid)| {
+        (Some(vid), prefs.get::<String>(&format!("pid.{}", index)))
+    }));
+
+    ids.into_iter().filter_map(|ids| {
+        match ids {
+            (Some(vid), Some(pid)) => parse_id(&vid).and_then(|vid| parse_id(&pid).map(|pid| (vid, pid))),
+            _ => None
+        }
+    }).collect()
+}
+
+pub fn detect_port(usb_ids: &[(u16, u16)]) -> Result<String> {
+    match_port(&available_ports(), usb_ids)
+}
+
+fn match_port(ports: &[SerialPort], usb_ids: &[(u16, u16)]) -> Result<String> {
+    let matching = ports.iter().filter(|port| {
+        port.usb_id.map_or(false, |usb_id| usb_ids.contains(&usb_id))
+    }).collect::<Vec<_>>();
+    let candidates = if matching.is_empty() { ports.iter().collect() } else { matching };
+
+    match candidates.len() {
+        0 => bail!("No serial ports found to detect the board on"),
+        1 => Ok(candidates[0].name.clone()),
+        _ => {
+            bail!("Multiple serial ports found, specify one with '--serial-port': {}",
+                  candidates.iter().map(|port| port.name.as_str()).collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+// Only ports with known USB IDs identify a board, which limits the detection to Linux.
+pub fn detect_board(boards: &[(BoardInfo, Vec<(u16, u16)>)]) -> Result<(BoardInfo, String)> {
+    match_board(&available_ports(), boards)
+}
+
+fn match_board(ports: &[SerialPort], boards: &[(BoardInfo, Vec<(u16, u16)>)]) -> Result<(BoardInfo, String)> {
+    let mut candidates = Vec::new();
+    for port in ports {
+        if let Some(usb_id) = port.usb_id {
+            for &(ref board, ref usb_ids) in boards {
+                if usb_ids.contains(&usb_id) {
+                    candidates.push((board, port.name.as_str()));
+                }
+            }
+        }
+    }
+
+    match candidates.len() {
+        0 if ports.is_empty() => bail!("No serial ports found to detect the board on"),
+        0 => {
+            bail!("None of the connected boards match an installed board definition, specify one with \
+                   '--target-board' (serial ports: {})",
+                  ports.iter().map(|port| port.name.as_str()).collect::<Vec<_>>().join(", "))
+        }
+        1 => Ok((candidates[0].0.clone(), candidates[0].1.to_string())),
+        _ => {
+            bail!("Multiple boards could be connected, specify one with '--target-board': {}",
+                  candidates.iter().map(|&(board, port)| format!("{} on {}", board, port)).collect::<Vec<_>>()
+                            .join(", "))
+        }
+    }
+}
+
+pub fn port_names() -> Vec<String> {
+    available_ports().into_iter().map(|port| port.name).collect()
+}
+
+// Opening the port at 1200 baud and closing it again makes native USB boards reset into their bootloader.
+pub fn touch(port: &str) -> Result<()> {
+    let mut command = touch_command(port);
+    let status = command.status().chain_err(|| format!("Could not run {:?} to reset the board", command))?;
+    if !status.success() {
+        bail!("Could not open serial port {} at 1200 baud to reset the board", port);
+    }
+    Ok(())
+}
+
+// The bootloader either shows up as a new port or re-enumerates under the name of the touched port.
+pub fn wait_for_bootloader(touched_port: &str, previous_ports: &[String], timeout: Duration) -> Result<String> {
+    let start = Instant::now();
+    let mut vanished = false;
+    while start.elapsed() < timeout {
+        thread::sleep(Duration::from_millis(250));
+
+        let ports = port_names();
+        if let Some(port) = ports.iter().find(|port| !previous_ports.contains(port)) {
+            return Ok(port.clone());
+        }
+        if !ports.iter().any(|port| port == touched_port) {
+            vanished = true;
+        } else if vanished {
+            return Ok(touched_port.to_string());
+        }
+    }
+
+    bail!("The board's bootloader did not appear on a serial port within {} seconds after resetting it \
+           through {} (ports before the reset: {})", timeout.as_secs(), touched_port, previous_ports.join(", "))
+}
+
+#[cfg(target_os = "macos")]
+fn touch_command(port: &str) -> Command {
+    let mut command = Command::new("stty");
+    command.args(&["-f", port, "1200"]);
+    command
+}
+
+#[cfg(windows)]
+fn touch_command(port: &str) -> Command {
+    let mut command = Command::new("mode");
+    command.arg(format!("{}:", port)).arg("BAUD=1200");
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn touch_command(port: &str) -> Command {
+    let mut command = Command::new("stty");
+    command.args(&["-F", port, "1200"]);
+    command
+}
+
+fn parse_id(id: &str) -> Option<u16> {
+    let id = id.trim();
+    let digits = if id.starts_with("0x") || id.starts_with("0X") { &id[2..] } else { id };
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(target_os = "linux")]
+fn available_ports() -> Vec<SerialPort> {
+    // Only USB serial devices are listed; the USB IDs are found in one of the parent devices in sysfs.
+    fs::read_dir("/sys/class/tty").map(|entries| {
+        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            fs::canonicalize(entry.path().join("device")).ok().and_then(|device| usb_id(&device)).map(|usb_id| {
+                SerialPort {
+                    name: format!("/dev/{}", entry.file_name().to_string_lossy()),
+                    usb_id: Some(usb_id)
+                }
+            })
+        }).collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(target_os = "linux")]
+fn usb_id(device: &Path) -> Option<(u16, u16)> {
+    let mut dir = Some(device);
+    while let Some(current) = dir {
+        if let (Some(vid), Some(pid)) = (read_id(&current.join("idVendor")), read_id(&current.join("idProduct"))) {
+            return Some((vid, pid));
+        }
+        dir = current.parent();
+    }
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn read_id(path: &Path) -> Option<u16> {
+    let mut id = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut id)).is_err() {
+        return None;
+    }
+    parse_id(&id)
+}
+
+#[cfg(target_os = "macos")]
+fn available_ports() -> Vec<SerialPort> {
+    fs::read_dir("/dev").map(|entries| {
+        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("cu.usb") {
+                Some(SerialPort {
+                    name: format!("/dev/{}", name),
+                    usb_id: None
+                })
+            } else {
+                None
+            }
+        }).collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn available_ports() -> Vec<SerialPort> {
+    // The serial device map lists the COM ports of all present devices.
+    Command::new("reg").args(&["query", r"HKLM\HARDWARE\DEVICEMAP\SERIALCOMM"]).output().map(|output| {
+        String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| {
+            line.split_whitespace().last().and_then(|name| {
+                if name.starts_with("COM") {
+                    Some(SerialPort {
+                        name: name.to_string(),
+                        usb_id: None
+                    })
+                } else {
+                    None
+                }
+            })
+        }).collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn available_ports() -> Vec<SerialPort> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_board, match_port, SerialPort};
+
+    use board::BoardInfo;
+
+    const UNO: (u16, u16) = (0x2341, 0x0043);
+    const LEONARDO: (u16, u16) = (0x2341, 0x8036);
+
+    fn port(name: &str, usb_id: Option<(u16, u16)>) -> SerialPort {
+        SerialPort {
+            name: name.to_string(),
+            usb_id: usb_id
+        }
+    }
+
+    fn boards() -> Vec<(BoardInfo, Vec<(u16, u16)>)> {
+        vec![
+            (BoardInfo::from_fqbn("arduino:avr:uno").unwrap(), vec![UNO, (0x2a03, 0x0043)]),
+            (BoardInfo::from_fqbn("arduino:avr:leonardo").unwrap(), vec![LEONARDO, (0x2341, 0x0036)])
+        ]
+    }
+
+    #[test]
+    fn board_is_matched_by_the_usb_id_of_its_port() {
+        let ports = [port("/dev/ttyS0", None), port("/dev/ttyUSB0", Some((0x0403, 0x6001))),
+                     port("/dev/ttyACM0", Some(LEONARDO))];
+        let (board, port_name) = match_board(&ports, &boards()).unwrap();
+        assert_eq!(board, BoardInfo::from_fqbn("arduino:avr:leonardo").unwrap());
+        assert_eq!(port_name, "/dev/ttyACM0");
+
+        let ports = [port("/dev/ttyACM1", Some((0x2a03, 0x0043)))];
+        let (board, port_name) = match_board(&ports, &boards()).unwrap();
+        assert_eq!(board, BoardInfo::from_fqbn("arduino:avr:uno").unwrap());
+        assert_eq!(port_name, "/dev/ttyACM1");
+    }
+
+    #[test]
+    fn unmatched_or_ambiguous_boards_are_errors() {
+        let error = match_board(&[], &boards()).unwrap_err().to_string();
+        assert!(error.contains("No serial ports found"), "{}", error);
+
+        let ports = [port("/dev/ttyUSB0", Some((0x0403, 0x6001)))];
+        let error = match_board(&ports, &boards()).unwrap_err().to_string();
+        assert!(error.contains("'--target-board' (serial ports: /dev/ttyUSB0)"), "{}", error);
+
+        let ports = [port("/dev/ttyACM0", Some(UNO)), port("/dev/ttyACM1", Some(LEONARDO))];
+        let error = match_board(&ports, &boards()).unwrap_err().to_string();
+        assert!(error.contains("arduino:avr:uno on /dev/ttyACM0, arduino:avr:leonardo on /dev/ttyACM1"), "{}", error);
+    }
+
+    #[test]
+    fn port_is_matched_by_usb_id_or_as_the_only_port() {
+        let ports = [port("/dev/ttyACM0", Some(LEONARDO)), port("/dev/ttyACM1", Some(UNO))];
+        assert_eq!(match_port(&ports, &[UNO]).unwrap(), "/dev/ttyACM1");
+        assert!(match_port(&ports, &[(0x0403, 0x6001)]).is_err());
+
+        // Without USB IDs any single port is used.
+        assert_eq!(match_port(&[port("COM3", None)], &[UNO]).unwrap(), "COM3");
+        assert!(match_port(&[], &[UNO]).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use error::{Result, ResultExt};
+
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Vec<Phase>
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings::default()
+    }
+
+    pub fn record<S: Into<String>>(&mut self, name: S, start: Instant) {
+        self.phases.push(Phase {
+            name: name.into(),
+            duration: seconds(start.elapsed())
+        });
+    }
+
+    pub fn write_report(self, recipes_file: &Path, report_file: &Path) -> Result<()> {
+        let recipes = match File::open(recipes_file) {
+            Ok(file) => {
+                BufReader::new(file).lines().filter_map(|line| {
+                    line.ok().and_then(|line| serde_json::from_str::<RecipeRun>(&line).ok())
+                }).collect()
+            }
+            Err(_) => Vec::new()
+        };
+
+        let mut sources = HashMap::<PathBuf, SourceTotal>::new();
+        for run in recipes.iter().filter(|run| run.recipe != "ar") {
+            let total = sources.entry(run.file.clone()).or_insert_with(|| SourceTotal {
+                source: run.file.clone(),
+                duration: 0.0,
+                runs: 0
+            });
+            total.duration += run.duration;
+            total.runs += 1;
+        }
+        let mut sources = sources.into_iter().map(|(_, total)| total).collect::<Vec<_>>();
+        sources.sort_by(|a, b| b.duration.partial_cmp(&a.duration).unwrap());
+
+        let report = Report {
+            phases: self.phases,
+            recipes: recipes,
+            sources: sources
+        };
+
+        fs::create_dir_all(report_file.parent().unwrap()).chain_err(|| "Could not create timings directory")?;
+        let mut file = File::create(report_file).chain_err(|| "Could not create timings report")?;
+        serde_json::to_writer_pretty(&mut file, &report).chain_err(|| "Could not write timings report")
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Phase {
+    name: String,
+    duration: f64
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RecipeRun {
+    recipe: String,
+    file: PathBuf,
+    duration: f64
+}
+
+#[derive(Debug, Serialize)]
+struct SourceTotal {
+    source: PathBuf,
+    duration: f64,
+    runs: usize
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    phases: Vec<Phase>,
+    recipes: Vec<RecipeRun>,
+    sources: Vec<SourceTotal>
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
@@ -0,0 +1,194 @@
+use Result;
+
+// See https://github.com/microsoft/uf2 for the format.
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+const BLOCK_SIZE: usize = 512;
+const PAYLOAD_SIZE: usize = 256;
+const DATA_SIZE: usize = 476;
+
+const PT_LOAD: u32 = 1;
+
+pub fn family_id(name: &str) -> Option<u32> {
+    match name.to_uppercase().as_str() {
+        "SAMD21" => Some(0x68ED_2B88),
+        "SAMD51" => Some(0x5511_4460),
+        "NRF52840" => Some(0xADA5_2840),
+        "RP2040" => Some(0xE48B_FF56),
+        "STM32F4" => Some(0x5775_5A57),
+        name if name.starts_with("0X") => u32::from_str_radix(&name[2..], 16).ok(),
+        _ => None
+    }
+}
+
+// Boards that do not name their family are recognized by their architecture and MCU.
+pub fn board_family_id(arch: &str, mcu: &str) -> Option<u32> {
+    match (arch, mcu) {
+        ("samd", "cortex-m4") => family_id("SAMD51"),
+        ("samd", _) => family_id("SAMD21"),
+        ("rp2040", _) => family_id("RP2040"),
+        ("nrf52", _) => family_id("NRF52840"),
+        _ => None
+    }
+}
+
+// The loadable segments of the ELF file are written to their load (physical) addresses, in blocks of 256 bytes.
+pub fn convert(elf: &[u8], family_id: Option<u32>) -> Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+    for (address, data) in load_segments(elf)? {
+        for (index, chunk) in data.chunks(PAYLOAD_SIZE).enumerate() {
+            chunks.push((address + (index * PAYLOAD_SIZE) as u32, chunk));
+        }
+    }
+    if chunks.is_empty() {
+        bail!("The ELF file has no loadable segments to convert to UF2");
+    }
+
+    let mut uf2 = Vec::with_capacity(chunks.len() * BLOCK_SIZE);
+    for (block_number, &(address, chunk)) in chunks.iter().enumerate() {
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            if family_id.is_some() { FLAG_FAMILY_ID_PRESENT } else { 0 },
+            address,
+            PAYLOAD_SIZE as u32,
+            block_number as u32,
+            chunks.len() as u32,
+            family_id.unwrap_or(0)
+        ];
+        for word in &header {
+            push_u32(&mut uf2, *word);
+        }
+        uf2.extend_from_slice(chunk);
+        uf2.extend(vec![0; DATA_SIZE - chunk.len()]);
+        push_u32(&mut uf2, MAGIC_END);
+    }
+    Ok(uf2)
+}
+
+fn load_segments(elf: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    if elf.len() < 0x34 || &elf[..4] != b"\x7fELF" || elf[4] != 1 || elf[5] != 1 {
+        bail!("Only little-endian 32-bit ELF files can be converted to UF2");
+    }
+
+    let program_headers = read_u32(elf, 0x1C)? as usize;
+    let header_size = read_u16(elf, 0x2A)? as usize;
+    let header_count = read_u16(elf, 0x2C)? as usize;
+
+    let mut segments = Vec::new();
+    for index in 0..header_count {
+        let header = program_headers + index * header_size;
+        let (kind, offset, address, size) = (read_u32(elf, header)?, read_u32(elf, header + 4)? as usize,
+                                             read_u32(elf, header + 12)?, read_u32(elf, header + 16)? as usize);
+        if kind == PT_LOAD && size > 0 {
+            let data = elf.get(offset..offset + size).map_or_else(|| Err("Truncated ELF segment"), Ok)?;
+            segments.push((address, data));
+        }
+    }
+    Ok(segments)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).map_or_else(|| Err("Truncated ELF header"), Ok)?;
+    Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).map_or_else(|| Err("Truncated ELF header"), Ok)?;
+    Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert, family_id, push_u32, read_u32};
+
+    // A little-endian ELF32 file with one program header per segment, followed by the segments' data.
+    fn elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut elf = b"\x7fELF\x01\x01\x01".to_vec();
+        elf.resize(0x34, 0);
+        elf[0x1C] = 0x34;
+        elf[0x2A] = 32;
+        elf[0x2C] = segments.len() as u8;
+
+        let mut offset = 0x34 + segments.len() * 32;
+        for &(kind, address, data) in segments {
+            for word in &[kind, offset as u32, address, address, data.len() as u32, data.len() as u32, 5, 4] {
+                push_u32(&mut elf, *word);
+            }
+            offset += data.len();
+        }
+        for &(_, _, data) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    fn header_word(block: &[u8], index: usize) -> u32 {
+        read_u32(block, index * 4).unwrap()
+    }
+
+    #[test]
+    fn a_single_segment_matches_uf2conv() {
+        let uf2 = convert(&elf(&[(1, 0x2000, &[1, 2, 3, 4][..])]), family_id("SAMD21")).unwrap();
+
+        // As written by `uf2conv.py -f SAMD21 -b 0x2000` for the same four bytes.
+        let mut expected = vec![
+            0x55, 0x46, 0x32, 0x0A, 0x57, 0x51, 0x5D, 0x9E, 0x00, 0x20, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x88, 0x2B, 0xED, 0x68,
+            0x01, 0x02, 0x03, 0x04
+        ];
+        expected.resize(508, 0);
+        expected.extend_from_slice(&[0x30, 0x6F, 0xB1, 0x0A]);
+        assert_eq!(uf2, expected);
+    }
+
+    #[test]
+    fn a_short_last_chunk_is_padded_to_a_full_payload() {
+        let data = (0..300).map(|byte| byte as u8).collect::<Vec<_>>();
+        let uf2 = convert(&elf(&[(1, 0x2000, &data[..])]), None).unwrap();
+        assert_eq!(uf2.len(), 2 * 512);
+
+        let last = &uf2[512..];
+        assert_eq!(header_word(last, 3), 0x2100);
+        assert_eq!(header_word(last, 4), 256);
+        assert_eq!(header_word(last, 5), 1);
+        assert_eq!(header_word(last, 6), 2);
+        assert_eq!(&last[32..76], &data[256..]);
+        assert!(last[76..508].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn the_family_id_flag_is_only_set_with_a_family() {
+        let elf = elf(&[(1, 0, &[0xFF][..])]);
+
+        let uf2 = convert(&elf, Some(0xE48B_FF56)).unwrap();
+        assert_eq!(header_word(&uf2, 2), 0x2000);
+        assert_eq!(header_word(&uf2, 7), 0xE48B_FF56);
+
+        let uf2 = convert(&elf, None).unwrap();
+        assert_eq!(header_word(&uf2, 2), 0);
+        assert_eq!(header_word(&uf2, 7), 0);
+    }
+
+    #[test]
+    fn only_complete_elf32_files_are_converted() {
+        assert!(convert(b"not an ELF file", None).is_err());
+
+        let mut elf64 = elf(&[(1, 0, &[0xFF][..])]);
+        elf64[4] = 2;
+        assert!(convert(&elf64, None).is_err());
+
+        let mut truncated = elf(&[(1, 0, &[1, 2, 3, 4][..])]);
+        truncated.truncate(truncated.len() - 2);
+        assert!(convert(&truncated, None).is_err());
+
+        // Segments that are not loaded, such as notes, leave nothing to convert.
+        assert!(convert(&elf(&[(4, 0, &[1, 2, 3, 4][..])]), None).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+#![cfg(unix)]
+
+extern crate tempdir;
+
+mod common;
+
+use common::*;
+
+use tempdir::TempDir;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+#[test]
+fn cargo_config_rustflags_reach_the_build() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let project_dir = home_dir.path().join("project");
+    write_build_config(home_dir.path());
+    fs::create_dir_all(project_dir.join(".cargo")).unwrap();
+    File::create(project_dir.join(".cargo/config")).unwrap()
+        .write_all(b"[build]\nrustflags = [\"--cfg\", \"from_build\"]\n\n\
+                     [target.'cfg(target_arch = \"avr\")']\nrustflags = [\"--cfg\", \"from_avr_target\"]\n\n\
+                     [target.'cfg(target_arch = \"arm\")']\nrustflags = \"--cfg from_arm_target\"\n")
+        .unwrap();
+
+    let output = carguino_in(home_dir.path(), &project_dir, &["build", "-b", "arduino:avr:uno"], cache_dir.path());
+    let rustflags = stdout(&output);
+    assert!(rustflags.contains("--cfg from_avr_target"), "{}", rustflags);
+    assert!(!rustflags.contains("from_arm_target") && !rustflags.contains("from_build"), "{}", rustflags);
+
+    let output = carguino_command(home_dir.path(), &project_dir, &["build", "-b", "arduino:avr:uno"], cache_dir.path())
+        .env("RUSTFLAGS", "--cfg from_env")
+        .output()
+        .unwrap();
+    let rustflags = stdout(&output);
+    assert!(rustflags.contains("--cfg from_env") && !rustflags.contains("from_avr_target"), "{}", rustflags);
+}
+
+#[test]
+fn build_without_binaries_explains_the_skipped_steps() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    let output = carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno"], cache_dir.path());
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("the package has no binary target"), "{}", stderr);
+}
+
+#[test]
+fn failed_build_without_rust_sources_explains_the_fix() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    let output = carguino_command(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno"], cache_dir.path())
+        .env("FAKE_RUSTC_SYSROOT", home_dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rustup component add rust-src"), "{}", stderr);
+}
+
+#[test]
+fn build_info_is_written_next_to_each_binary() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    let artifact = home_dir.path().join("target/blink.elf");
+    fs::create_dir_all(artifact.parent().unwrap()).unwrap();
+
+    let output = carguino_command(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--build-info"],
+                                  cache_dir.path())
+        .env("FAKE_XARGO_ARTIFACT", &artifact)
+        .output()
+        .unwrap();
+    stdout(&output);
+
+    let mut build_info = String::new();
+    File::open(home_dir.path().join("target/blink.buildinfo.json")).unwrap().read_to_string(&mut build_info).unwrap();
+    assert!(build_info.contains("\"board\": \"arduino:avr:uno\""), "{}", build_info);
+    assert!(build_info.contains("\"rustc\": \"rustc 1.17.0-nightly (fixture)\""), "{}", build_info);
+    assert!(build_info.contains("\"profile\": \"debug\""), "{}", build_info);
+}
+
+#[test]
+fn rustc_is_asked_for_its_version_once() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    let log_file = home_dir.path().join("tools.log");
+
+    for _ in 0..2 {
+        let output = carguino_command(home_dir.path(), home_dir.path(),
+                                      &["build", "-b", "arduino:avr:uno", "--build-info"], cache_dir.path())
+            .env("FAKE_TOOL_LOG", &log_file)
+            .output()
+            .unwrap();
+        stdout(&output);
+    }
+
+    let mut log = String::new();
+    File::open(&log_file).unwrap().read_to_string(&mut log).unwrap();
+    assert_eq!(log.lines().filter(|run| *run == "rustc --version").count(), 2, "{}", log);
+    // The built-in target is only printed for the first build, then taken from the cache.
+    assert_eq!(log.lines().filter(|run| run.contains("target-spec-json")).count(), 1, "{}", log);
+}
+
+#[test]
+fn enabled_features_are_passed_on_as_defines() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    fs::OpenOptions::new().append(true).open(home_dir.path().join(".carguino/config")).unwrap()
+        .write_all(b"[feature-defines]\nleds = \"ENABLE_LEDS\"\nserial = \"ENABLE_SERIAL=1\"\n\
+                     sleep = \"ENABLE_SLEEP\"\n")
+        .unwrap();
+
+    let output = stdout(&carguino_in(home_dir.path(), home_dir.path(),
+                                     &["build", "-b", "arduino:avr:uno", "--features", "logging"], cache_dir.path()));
+    assert!(output.contains(r#""-DENABLE_LEDS""#), "{}", output);
+    assert!(output.contains(r#""-DENABLE_SERIAL=1""#), "{}", output);
+    assert!(!output.contains("ENABLE_SLEEP"), "{}", output);
+}
+
+#[test]
+fn include_dirs_reach_the_build_script() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    fs::create_dir(home_dir.path().join("include")).unwrap();
+
+    let output = carguino_in(home_dir.path(), home_dir.path(),
+                             &["build", "-b", "arduino:avr:uno", "--include-dir", "include", "--include-dir=missing"],
+                             cache_dir.path());
+    let include_dirs = format!(r#""include_dirs":["{}","{}"]"#, home_dir.path().join("include").display(),
+                               home_dir.path().join("missing").display());
+    assert!(stdout(&output).contains(&include_dirs), "{}", stdout(&output));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Include directory '") && stderr.contains("missing' does not exist"), "{}", stderr);
+}
+
+#[test]
+fn arguments_after_double_dash_reach_xargo_unchanged() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    fs::OpenOptions::new().append(true).open(home_dir.path().join(".carguino/config")).unwrap()
+        .write_all(b"[defaults]\nargs = [\"--release\"]\n").unwrap();
+
+    let output = carguino_in(home_dir.path(), home_dir.path(),
+                             &["rustc", "-b", "arduino:avr:uno", "--", "--cfg", "feature=\"a b\"", "--release",
+                               "--target", "thumbv7m-none-eabi"],
+                             cache_dir.path());
+    let stdout = stdout(&output);
+    let args = stdout.lines().filter(|line| line.starts_with("ARG=")).collect::<Vec<_>>();
+    let forwarded = &args[args.iter().position(|&arg| arg == "ARG=--release").unwrap()..];
+    assert_eq!(forwarded, ["ARG=--release", "ARG=--", "ARG=--cfg", "ARG=feature=\"a b\"", "ARG=--release",
+                           "ARG=--target", "ARG=thumbv7m-none-eabi"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Do not specify a target triple"));
+}
+
+#[test]
+fn opt_level_reaches_the_rustflags() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    let output = carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--opt-level", "s"],
+                             cache_dir.path());
+    let stdout = stdout(&output);
+    let rustflags = stdout.lines().find(|line| line.starts_with("RUSTFLAGS=")).unwrap();
+    assert!(rustflags.ends_with("-C opt-level=s"), "{}", rustflags);
+
+    let missing = carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--opt-level"],
+                              cache_dir.path());
+    assert_eq!(missing.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("Expected argument for option '--opt-level'"));
+}
+
+#[test]
+fn json_messages_are_forwarded_unchanged() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    let artifact = home_dir.path().join("target/blink.elf");
+    fs::create_dir_all(artifact.parent().unwrap()).unwrap();
+
+    let output = carguino_command(home_dir.path(), home_dir.path(),
+                                  &["build", "-b", "arduino:avr:uno", "--message-format", "json"], cache_dir.path())
+        .env("FAKE_XARGO_ARTIFACT", &artifact)
+        .output()
+        .unwrap();
+    let stdout = stdout(&output);
+    // As printed by the fake xargo, which runs in the (physical) project directory.
+    let message = format!("{{\"reason\": \"compiler-artifact\", \"package_id\": \"blink 0.1.0 (path+file://{})\", \
+                           \"target\": {{\"kind\": [\"bin\"], \"name\": \"blink\"}}, \"profile\": {{\"test\": false}}, \
+                           \"filenames\": [\"{}\"]}}\n",
+                          fs::canonicalize(home_dir.path()).unwrap().display(), artifact.display());
+    assert!(stdout.contains(&message), "{}", stdout);
+    assert!(!stdout.contains("\"phase\""), "{}", stdout);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(r#"{"phase":"Configuring","level":"info","message":"Arduino/Genuino Uno"}"#), "{}", stderr);
+}
+
+#[test]
+fn release_builds_compile_the_core_with_the_release_optimization_flags() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_config(home_dir.path(), "[arduino-builder.preferences]\n\
+                                   \"build.core.path\" = \"/nonexistent/cores/arduino\"\n\
+                                   \"compiler.optimization_flags\" = \"-Os\"\n\
+                                   \"compiler.optimization_flags.debug\" = \"-Og -g\"\n\
+                                   \"compiler.optimization_flags.release\" = \"-O2\"\n\
+                                   \"recipe.c.o.pattern\" = \"avr-gcc -c {compiler.optimization_flags} -o {object_file}\"\n\
+                                   \"recipe.cpp.o.pattern\" = \"avr-g++ -c {compiler.optimization_flags} -o {object_file}\"\n");
+
+    let debug = stdout(&carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno"],
+                                    cache_dir.path()));
+    assert!(debug.contains("avr-gcc -c -Og -g -o"), "{}", debug);
+    let release = stdout(&carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--release"],
+                                      cache_dir.path()));
+    assert!(release.contains("avr-gcc -c -O2 -o"), "{}", release);
+}
+
+#[test]
+fn messages_have_no_colors_when_not_written_to_a_terminal() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    let output = carguino_command(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno"], cache_dir.path())
+        .env("TERM", "xterm-256color")
+        .output()
+        .unwrap();
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Configuring") && !stderr.contains('\x1b'), "{:?}", stderr);
+
+    let without_board = carguino(&["build"], cache_dir.path());
+    let stderr = String::from_utf8_lossy(&without_board.stderr);
+    assert!(stderr.contains("No target-board was specified") && !stderr.contains('\x1b'), "{:?}", stderr);
+}
+
+#[test]
+fn doc_builds_for_the_board_with_its_cfgs() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    let output = stdout(&carguino_in(home_dir.path(), home_dir.path(), &["doc", "-b", "arduino:avr:uno"],
+                                     cache_dir.path()));
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(&lines[..3], ["ARG=doc", "ARG=--target", "ARG=avr-arduino-uno"]);
+    let rustdocflags = lines.iter().find(|line| line.starts_with("RUSTDOCFLAGS=")).unwrap();
+    assert!(rustdocflags.ends_with(r#"--cfg arduino_arch="avr" --cfg arduino_mcu="atmega328p""#), "{}", rustdocflags);
+    // Nothing is linked, so the build scripts do not compile the core.
+    assert!(output.contains(r#""link":false"#), "{}", output);
+}
+
+#[test]
+fn config_overrides_reach_every_cargo_run() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+    let log_file = home_dir.path().join("tools.log");
+
+    let output = carguino_command(home_dir.path(), home_dir.path(),
+                                  &["build", "-b", "arduino:avr:uno", "--two-pass", "--config", "build.jobs=1"],
+                                  cache_dir.path())
+        .env("FAKE_TOOL_LOG", &log_file)
+        .output()
+        .unwrap();
+    stdout(&output);
+
+    let mut log = String::new();
+    File::open(&log_file).unwrap().read_to_string(&mut log).unwrap();
+    let runs = log.lines().collect::<Vec<_>>();
+    assert_eq!(runs.iter().filter(|run| run.starts_with("cargo metadata ")).count(), 1, "{}", log);
+    assert_eq!(runs.iter().filter(|run| run.starts_with("xargo build ")).count(), 2, "{}", log);
+    assert!(runs.iter().filter(|run| !run.starts_with("rustc ")).all(|run| run.contains(" --config build.jobs=1")),
+            "{}", log);
+}
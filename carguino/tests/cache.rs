@@ -0,0 +1,45 @@
+#![cfg(unix)]
+
+extern crate tempdir;
+
+mod common;
+
+use common::*;
+
+use tempdir::TempDir;
+
+use std::fs::{self, File};
+use std::io::Write;
+
+#[test]
+fn cache_clear_removes_only_the_selected_caches() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    for dir in &["prefs", "targets"] {
+        fs::create_dir_all(cache_dir.path().join(dir)).unwrap();
+        File::create(cache_dir.path().join(dir).join("cached")).unwrap().write_all(b"12345").unwrap();
+    }
+
+    let info = stdout(&carguino(&["cache", "info"], cache_dir.path()));
+    assert!(info.contains("prefs:           5 bytes"), "{}", info);
+    assert!(info.contains("total:           10 bytes"), "{}", info);
+
+    stdout(&carguino(&["cache", "clear", "--prefs"], cache_dir.path()));
+    assert!(!cache_dir.path().join("prefs").exists());
+    assert!(cache_dir.path().join("targets/cached").is_file());
+
+    let output = carguino(&["cache", "clear"], cache_dir.path());
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn cache_dir_variable_relocates_the_specifications_and_prefs() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_build_config(home_dir.path());
+
+    stdout(&carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno"], cache_dir.path()));
+    assert!(cache_dir.path().join("targets/avr-arduino-uno.json").is_file());
+    assert_eq!(fs::read_dir(cache_dir.path().join("prefs")).unwrap().count(), 1);
+    assert!(!home_dir.path().join(".carguino/targets").exists());
+    assert!(!home_dir.path().join(".carguino/prefs").exists());
+}
@@ -0,0 +1,69 @@
+// Helpers shared by the integration tests; not every test file uses all of them.
+#![allow(dead_code)]
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+// Runs carguino in an empty directory, with the fake arduino-builder from the fixtures first on the path.
+pub fn carguino(args: &[&str], cache_dir: &Path) -> Output {
+    let work_dir = TempDir::new("carguino-test").unwrap();
+    carguino_in(work_dir.path(), work_dir.path(), args, cache_dir)
+}
+
+pub fn carguino_in(home_dir: &Path, current_dir: &Path, args: &[&str], cache_dir: &Path) -> Output {
+    carguino_command(home_dir, current_dir, args, cache_dir).output().unwrap()
+}
+
+pub fn carguino_command(home_dir: &Path, current_dir: &Path, args: &[&str], cache_dir: &Path) -> Command {
+    let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut path = OsString::from(fixtures_dir);
+    if let Some(system_path) = env::var_os("PATH") {
+        path.push(":");
+        path.push(system_path);
+    }
+
+    let mut command = Command::new(carguino_path());
+    command.args(args)
+           .current_dir(current_dir)
+           .env("HOME", home_dir)
+           .env("PATH", path)
+           .env("CARGUINO_CACHE_DIR", cache_dir)
+           .env("CARGO_HOME", home_dir.join(".cargo"))
+           .env_remove("ARDUINO_HOME")
+           .env_remove("RUSTFLAGS");
+    command
+}
+
+// Integration tests are placed in `target/<profile>/deps`, next to which the binary is built.
+pub fn carguino_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join("carguino")
+}
+
+pub fn write_config(dir: &Path, config: &str) {
+    fs::create_dir_all(dir.join(".carguino")).unwrap();
+    File::create(dir.join(".carguino/config")).unwrap().write_all(config.as_bytes()).unwrap();
+}
+
+// The fake toolchain needs the compile recipes and the core's location to configure a build.
+pub fn write_build_config(dir: &Path) {
+    write_config(dir, "[arduino-builder.preferences]\n\
+                       \"build.core.path\" = \"/nonexistent/cores/arduino\"\n\
+                       \"recipe.c.o.pattern\" = \"avr-gcc -c -o {object_file} {source_file}\"\n\
+                       \"recipe.cpp.o.pattern\" = \"avr-g++ -c -o {object_file} {source_file}\"\n");
+}
+
+pub fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "carguino failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
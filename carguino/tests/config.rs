@@ -0,0 +1,117 @@
+#![cfg(unix)]
+
+extern crate tempdir;
+
+mod common;
+
+use common::*;
+
+use tempdir::TempDir;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+#[test]
+fn nested_configs_are_merged() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let project_dir = home_dir.path().join("workspace/project");
+    write_config(home_dir.path(), "[target-board]\nvendor = \"arduino\"\narch = \"avr\"\nboard = \"uno\"\n");
+    write_config(&home_dir.path().join("workspace"),
+                 "[arduino-builder]\nhardware = []\ntools = []\nlibraries = []\ntemp-dir = \"/tmp/workspace\"\n");
+    write_config(&project_dir,
+                 "[arduino-builder]\nhardware = []\ntools = []\nlibraries = []\npost-build-command = \"true\"\n");
+
+    let output = stdout(&carguino_in(home_dir.path(), &project_dir, &["build", "--dump-config"], cache_dir.path()));
+    assert!(output.contains("target-board = \"arduino:avr:uno\""), "{}", output);
+    assert!(output.contains("temp-dir = \"/tmp/workspace\""), "{}", output);
+    assert!(output.contains("post-build-command = \"true\""), "{}", output);
+}
+
+#[test]
+fn default_args_are_overridden_by_the_command_line() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_config(home_dir.path(), "[defaults]\nargs = [\"--release\", \"--features\", \"a\"]\n");
+
+    let output = carguino_in(home_dir.path(), home_dir.path(), &["build", "--features", "b"], cache_dir.path());
+    assert_eq!(stdout(&output), "build --message-format human --release --features b\n");
+}
+
+#[test]
+fn manifest_path_selects_the_project() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let project_dir = home_dir.path().join("project");
+    let sibling_dir = home_dir.path().join("sibling");
+    fs::create_dir_all(&sibling_dir).unwrap();
+    write_config(&project_dir, "[defaults]\nargs = [\"--release\"]\n");
+
+    let output = carguino_in(home_dir.path(), &sibling_dir, &["build", "--manifest-path", "../project/Cargo.toml"],
+                             cache_dir.path());
+    assert_eq!(stdout(&output), format!("build --message-format human --manifest-path {} --release\n",
+                                        sibling_dir.join("../project/Cargo.toml").display()));
+}
+
+#[test]
+fn tool_directories_can_be_overridden() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let args_file = home_dir.path().join("arduino-builder-args");
+    write_config(home_dir.path(), "[arduino-builder]\nhardware = []\ntools = []\nlibraries = []\n\
+                                   avr-tools = \"/opt/avr\"\ntools-builder = \"/usr/lib/arduino-builder/tools\"\n");
+
+    let output = carguino_command(home_dir.path(), home_dir.path(), &["print", "cfgs", "-b", "arduino:avr:uno"],
+                                  cache_dir.path())
+        .env("ARDUINO_BUILDER_ARGS_FILE", &args_file)
+        .output()
+        .unwrap();
+    stdout(&output);
+
+    let mut args = String::new();
+    File::open(&args_file).unwrap().read_to_string(&mut args).unwrap();
+    assert!(args.contains("-tools /opt/avr -tools /usr/lib/arduino-builder/tools "), "{}", args);
+}
+
+#[test]
+fn boards_can_be_selected_by_name() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_config(home_dir.path(), "default-board = \"uno\"\n\n\
+                                   [[boards]]\nname = \"uno\"\nfqbn = \"arduino:avr:uno\"\n");
+
+    let by_name = carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "-b", "uno"], cache_dir.path());
+    assert_eq!(stdout(&by_name), "triple=avr-atmel-none\nspec=avr-arduino-uno\ncpu=atmega328p\n");
+    let by_default = carguino_in(home_dir.path(), home_dir.path(), &["print", "target"], cache_dir.path());
+    assert_eq!(stdout(&by_default), "triple=avr-atmel-none\nspec=avr-arduino-uno\ncpu=atmega328p\n");
+    let missing = carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "-b", "nano"], cache_dir.path());
+    assert_eq!(missing.status.code(), Some(3));
+}
+
+#[test]
+fn explicit_config_file_overrides_discovered_configs() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    write_config(home_dir.path(), "target-board = \"arduino:avr:uno\"\n");
+    let config_file = home_dir.path().join("ci.toml");
+    File::create(&config_file).unwrap().write_all(b"target-board = \"arduino:avr:mega\"\n").unwrap();
+
+    let output = carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "--config-file", "ci.toml"],
+                             cache_dir.path());
+    assert!(stdout(&output).contains("spec=avr-arduino-mega\n"));
+
+    let missing = carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "--config-file", "missing.toml"],
+                              cache_dir.path());
+    assert_eq!(missing.status.code(), Some(3));
+}
+
+#[test]
+fn upload_options_without_a_value_are_argument_errors() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    for option in &["--upload-retries", "--upload-timeout"] {
+        let output = carguino(&["upload", "-b", "arduino:avr:uno", *option], cache_dir.path());
+        assert_eq!(output.status.code(), Some(3));
+        let expected = format!("Expected argument for option '{}'", option);
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&expected));
+    }
+}
@@ -0,0 +1,103 @@
+#![cfg(unix)]
+
+extern crate tempdir;
+
+mod common;
+
+use common::*;
+
+use tempdir::TempDir;
+
+use std::fs::File;
+use std::io::Read;
+
+#[test]
+fn print_cfgs() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let output = carguino(&["print", "cfgs", "--target-board", "arduino:avr:uno"], cache_dir.path());
+    assert_eq!(stdout(&output), "arduino_arch=\"avr\"\narduino_mcu=\"atmega328p\"\n");
+}
+
+#[test]
+fn print_cfgs_with_short_board_option() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let separate = carguino(&["print", "cfgs", "-b", "arduino:avr:uno"], cache_dir.path());
+    let joined = carguino(&["print", "cfgs", "-b=arduino:avr:uno"], cache_dir.path());
+    assert_eq!(stdout(&separate), "arduino_arch=\"avr\"\narduino_mcu=\"atmega328p\"\n");
+    assert_eq!(stdout(&separate), stdout(&joined));
+}
+
+#[test]
+fn options_before_the_command_are_used() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let output = carguino(&["-b", "arduino:avr:uno", "--menu", "cpu=atmega168", "print", "cfgs"], cache_dir.path());
+    assert_eq!(stdout(&output), "arduino_arch=\"avr\"\narduino_mcu=\"atmega168\"\n");
+}
+
+#[test]
+fn print_target() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let output = carguino(&["print", "target", "--target-board", "arduino:avr:uno"], cache_dir.path());
+    assert_eq!(stdout(&output), "triple=avr-atmel-none\nspec=avr-arduino-uno\ncpu=atmega328p\n");
+}
+
+#[test]
+fn cached_prefs_are_reused() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let first = carguino(&["print", "cfgs", "--target-board", "arduino:avr:uno"], cache_dir.path());
+    let second = carguino(&["print", "cfgs", "--target-board", "arduino:avr:uno", "-v"], cache_dir.path());
+    assert_eq!(stdout(&first), stdout(&second));
+    assert!(String::from_utf8_lossy(&second.stderr).contains("Fresh build settings"));
+}
+
+#[test]
+fn print_without_board_is_an_argument_error() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let output = carguino(&["print", "cfgs"], cache_dir.path());
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn mcu_is_mapped_to_the_llvm_cpu() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let print_target = |arch: &str, mcu: &str| {
+        let home_dir = TempDir::new("carguino-test").unwrap();
+        write_config(home_dir.path(), &format!("[arduino-builder.preferences]\n\
+                                                \"build.arch\" = \"{}\"\n\"build.mcu\" = \"{}\"\n", arch, mcu));
+        carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "-b", "arduino:avr:uno"], cache_dir.path())
+    };
+
+    assert!(stdout(&print_target("avr", "atmega32u4")).ends_with("cpu=atmega32u4\n"));
+    assert!(stdout(&print_target("avr", "atmega328pb")).ends_with("cpu=atmega328p\n"));
+    let cortex = print_target("sam", "cortex-m0+");
+    assert!(stdout(&cortex).starts_with("triple=thumbv6m-none-eabi\n"));
+    assert!(stdout(&cortex).ends_with("cpu=cortex-m0plus\n"));
+    assert!(stdout(&print_target("sam", "cortex-m4")).ends_with("cpu=cortex-m4\n"));
+
+    let unknown = print_target("avr", "atmega9999");
+    assert!(stdout(&unknown).ends_with("cpu=atmega9999\n"));
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("Unknown MCU 'atmega9999'"));
+}
+
+#[test]
+fn menu_options_change_the_target_cpu() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let print_target = |board: &str| {
+        stdout(&carguino_in(home_dir.path(), home_dir.path(), &["print", "target", "-b", board], cache_dir.path()))
+    };
+
+    assert!(print_target("arduino:avr:uno").ends_with("cpu=atmega328p\n"));
+    let atmega168 = print_target("arduino:avr:uno:cpu=atmega168");
+    assert!(atmega168.contains("spec=avr-arduino-uno-cpu_atmega168\n"), "{}", atmega168);
+    assert!(atmega168.ends_with("cpu=atmega168\n"), "{}", atmega168);
+    assert!(print_target("arduino:avr:uno:cpu=atmega328").ends_with("cpu=atmega328p\n"));
+
+    let spec_file = home_dir.path().join("spec.json");
+    stdout(&carguino_in(home_dir.path(), home_dir.path(),
+                        &["build", "-b", "arduino:avr:uno", "--menu", "cpu=atmega168", "--emit-spec", "spec.json"],
+                        cache_dir.path()));
+    let mut spec = String::new();
+    File::open(&spec_file).unwrap().read_to_string(&mut spec).unwrap();
+    assert!(spec.contains(r#""cpu": "atmega168""#), "{}", spec);
+}
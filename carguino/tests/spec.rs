@@ -0,0 +1,117 @@
+#![cfg(unix)]
+
+extern crate serde_json;
+extern crate tempdir;
+
+mod common;
+
+use common::*;
+
+use serde_json::Value;
+use tempdir::TempDir;
+
+use std::fs::{self, File};
+use std::io::Read;
+
+// Emits the specification of the fixture's Uno, with the given options, in a fresh project.
+fn emit_spec(options: &[&str]) -> Value {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+
+    let mut args = vec!["build", "-b", "arduino:avr:uno", "--emit-spec", "uno.json"];
+    args.extend_from_slice(options);
+    stdout(&carguino_in(home_dir.path(), home_dir.path(), &args, cache_dir.path()));
+    serde_json::from_reader(File::open(home_dir.path().join("uno.json")).unwrap()).unwrap()
+}
+
+fn strings(value: &Value) -> Vec<&str> {
+    value.as_array().unwrap().iter().map(|value| value.as_str().unwrap()).collect()
+}
+
+#[test]
+fn emit_spec_writes_the_target_specification_without_building() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let spec_file = home_dir.path().join("uno.json");
+
+    let output = carguino_in(home_dir.path(), home_dir.path(),
+                             &["build", "-b", "arduino:avr:uno", "--emit-spec", "uno.json"], cache_dir.path());
+    assert_eq!(stdout(&output), "");
+    let mut spec = String::new();
+    File::open(&spec_file).unwrap().read_to_string(&mut spec).unwrap();
+    assert!(spec.contains("\"cpu\": \"atmega328p\""), "{}", spec);
+
+    let missing_dir = carguino_in(home_dir.path(), home_dir.path(),
+                                  &["build", "-b", "arduino:avr:uno", "--emit-spec", "missing/uno.json"],
+                                  cache_dir.path());
+    assert_eq!(missing_dir.status.code(), Some(2));
+}
+
+#[test]
+fn linker_override_is_used_in_the_target_specification() {
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let home_dir = TempDir::new("carguino-test").unwrap();
+    let linker = home_dir.path().join("ld-wrapper");
+    File::create(&linker).unwrap();
+
+    let output = carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--linker",
+                                                                 "./ld-wrapper", "--emit-spec", "uno.json"],
+                             cache_dir.path());
+    stdout(&output);
+    let mut spec = String::new();
+    File::open(home_dir.path().join("uno.json")).unwrap().read_to_string(&mut spec).unwrap();
+    let expected = format!("\"linker\": \"{}\"", fs::canonicalize(&linker).unwrap().display());
+    assert!(spec.contains(&expected), "{}", spec);
+
+    let missing = carguino_in(home_dir.path(), home_dir.path(), &["build", "-b", "arduino:avr:uno", "--linker",
+                                                                  "./missing-ld", "--emit-spec", "uno.json"],
+                              cache_dir.path());
+    assert_eq!(missing.status.code(), Some(2));
+}
+
+#[test]
+fn the_uno_specification_is_derived_from_the_combine_recipe() {
+    let spec = emit_spec(&[]);
+    assert_eq!(spec["llvm-target"].as_str(), Some("avr-unknown-unknown"));
+    assert_eq!(spec["linker"].as_str(), Some("/nonexistent/hardware/tools/avr/bin/avr-gcc"));
+    assert_eq!(spec["cpu"].as_str(), Some("atmega328p"));
+    assert_eq!(spec["is-builtin"].as_bool(), Some(false));
+    assert_eq!(spec["linker-is-gnu"].as_bool(), Some(true));
+    // Only the options the linker needs are taken from the recipe; its optimization flags and outputs are not.
+    assert_eq!(strings(&spec["pre-link-args"]), vec!["-mmcu=atmega328p"]);
+    assert_eq!(strings(&spec["late-link-args"]), vec!["-lm"]);
+}
+
+#[test]
+fn the_board_mcu_is_passed_to_the_avr_linker() {
+    let spec = emit_spec(&["-b", "arduino:avr:uno:cpu=atmega168"]);
+    assert_eq!(spec["cpu"].as_str(), Some("atmega168"));
+    assert!(strings(&spec["pre-link-args"]).contains(&"-mmcu=atmega168"), "{}", spec);
+}
+
+#[test]
+fn defsyms_are_passed_to_the_linker() {
+    let spec = emit_spec(&["--defsym", "__stack=0x8ff", "--defsym=__heap_end=0x8f0"]);
+    let pre_link_args = strings(&spec["pre-link-args"]);
+    assert!(pre_link_args.contains(&"-Wl,--defsym=__stack=0x8ff"), "{}", spec);
+    assert!(pre_link_args.contains(&"-Wl,--defsym=__heap_end=0x8f0"), "{}", spec);
+
+    let cache_dir = TempDir::new("carguino-cache").unwrap();
+    let invalid = carguino(&["build", "-b", "arduino:avr:uno", "--defsym", "__stack", "--emit-spec", "uno.json"],
+                           cache_dir.path());
+    assert_eq!(invalid.status.code(), Some(3));
+}
+
+#[test]
+fn link_toggles_change_the_target_specification() {
+    let default = emit_spec(&[]);
+    assert!(!strings(&default["late-link-args"]).contains(&"-Wl,--no-gc-sections"), "{}", default);
+
+    let spec = emit_spec(&["--link-arg", "-Wl,--print-memory-usage", "--link-arg=-Wl,-Map,firmware.map",
+                           "--no-gc-sections"]);
+    let pre_link_args = strings(&spec["pre-link-args"]);
+    assert!(pre_link_args.contains(&"-Wl,--print-memory-usage"), "{}", spec);
+    assert!(pre_link_args.contains(&"-Wl,-Map,firmware.map"), "{}", spec);
+    // It has to come after rustc's own `--gc-sections` to override it.
+    assert_eq!(strings(&spec["late-link-args"]).last(), Some(&"-Wl,--no-gc-sections"));
+}